@@ -1,29 +1,187 @@
 //! `cli` mod is used to read and parse command line arguments. These arguments
 //! are required by `config` mod to produce a valid configuration before `core`
 //! starts.
-use std::path::PathBuf;
+//!
+//! Installed as `mount.megafuse`, the binary follows the `mount(8)` helper
+//! convention instead, so mounts can be declared in `/etc/fstab`:
+//!
+//! ```text
+//! mega@mega.example.com:8000 /mnt/mega megafuse cache_dir=/var/cache/mega,allow_other,_netdev 0 0
+//! ```
+use std::{collections::HashMap, net::SocketAddr, path::Path, path::PathBuf, time::Duration};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+/// Prefix of the names the binary acts as a `mount(8)` helper under, e.g.
+/// `mount.megafuse` or `mount.fuse.megafuse`
+const MOUNT_HELPER_PREFIX: &str = "mount.";
+
+/// Options `mount(8)` handles itself or passes for other tools, ignored by the
+/// helper. `rw` is implied by `defaults`, the mount is read-only regardless.
+const IGNORED_MOUNT_OPTIONS: [&str; 7] = [
+    "defaults", "auto", "noauto", "nouser", "nofail", "_netdev", "rw",
+];
+
+/// Options letting users mount, and the options they imply as for `mount(8)`:
+/// later options may override them, e.g. `user,exec`.
+const USER_MOUNT_OPTIONS: [(&str, &[&str]); 4] = [
+    ("user", &["noexec", "nosuid", "nodev"]),
+    ("users", &["noexec", "nosuid", "nodev"]),
+    ("owner", &["nosuid", "nodev"]),
+    ("group", &["nosuid", "nodev"]),
+];
+
+/// Timeout used for "infinite": large enough to never expire in practice while
+/// still fitting the kernel's signed seconds.
+pub const INFINITE_TIMEOUT: Duration = Duration::from_secs(u32::MAX as u64);
 
 /// Command line parser definition
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Args {
+    /// Configuration file in TOML, keyed by the long options; options given
+    /// on the command line take precedence
+    #[arg(long)]
+    pub config: Option<PathBuf>,
     /// Mount point
     #[arg(long)]
     pub mount_point: Option<PathBuf>,
     /// Cache directory: where the data pulled from remote actually resides
     #[arg(long, default_value = None/* TODO */)]
     pub cache_dir: Option<PathBuf>,
-    /// Log directory
+    /// Log directory, logs go to stdout without one
     #[arg(long, default_value = None/* TODO */)]
     pub log_dir: Option<PathBuf>,
+    /// Log filter: a level such as `debug`, or per-module directives such as
+    /// `info,fuse::core=trace`. Takes precedence over `RUST_LOG`
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Format of the log lines
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// How often the log file in `--log-dir` is rotated
+    #[arg(long, value_enum)]
+    pub log_rotation: Option<LogRotation>,
+    /// Number of log files kept in `--log-dir`, all of them by default
+    #[arg(long)]
+    pub log_max_files: Option<usize>,
+    /// Record the spans of FUSE requests, HTTP requests and cache accesses to
+    /// this file in Chrome trace format
+    #[arg(long)]
+    pub trace_file: Option<PathBuf>,
+    /// Record which processes open and read which files to `access.log` in
+    /// `--log-dir`
+    #[arg(long)]
+    pub access_log: bool,
     /// Mega server Host
     #[arg(long)]
     pub mega_host: Option<String>,
     /// Mega server Port
     #[arg(long)]
     pub mega_port: Option<u16>,
+    /// Path the Mega API is served under [default: /api]
+    #[arg(long, value_name = "PATH")]
+    pub api_base: Option<String>,
+    /// Version of the Mega API [default: v1]
+    #[arg(long)]
+    pub api_version: Option<String>,
+    /// Directory of the monorepo holding the repositories [default: /projects]
+    #[arg(long, value_name = "PATH")]
+    pub repo_root: Option<String>,
+    /// HTTP version spoken with the Mega server [default: auto]
+    #[arg(long, value_enum)]
+    pub http_version: Option<HttpVersion>,
+    /// Compression asked of the Mega server for trees and blobs [default: auto]
+    #[arg(long, value_enum)]
+    pub compression: Option<Compression>,
+    /// Seconds to wait for a connection to the Mega server, or "infinite"
+    /// [default: 10]
+    #[arg(long, value_parser = parse_timeout)]
+    pub connect_timeout: Option<Duration>,
+    /// Seconds to wait for the server to start answering a request, or
+    /// "infinite" [default: 30]
+    #[arg(long, value_parser = parse_timeout)]
+    pub first_byte_timeout: Option<Duration>,
+    /// Seconds a whole request, download included, may take, or "infinite"
+    /// [default: 300]
+    #[arg(long, value_parser = parse_timeout)]
+    pub request_timeout: Option<Duration>,
+    /// Mount the last known tree without contacting the Mega server
+    #[arg(long)]
+    pub offline: bool,
+    /// Detach from the terminal once mounted
+    #[arg(long)]
+    pub background: bool,
+    /// Bypass the kernel page cache for every opened file (breaks `mmap`)
+    #[arg(long)]
+    pub direct_io: bool,
+    /// Expose a read-only `.git` directory with the mounted commit as `HEAD`,
+    /// so `git log`, `git show` and `git blame` work in the mount (no index:
+    /// `git status` is unsupported)
+    #[arg(long)]
+    pub git_view: bool,
+    /// Owner of every file, the user mounting by default
+    #[arg(long)]
+    pub uid: Option<u32>,
+    /// Group of every file, the group of the user mounting by default
+    #[arg(long)]
+    pub gid: Option<u32>,
+    /// Permission bits, in octal, cleared from every file and directory
+    #[arg(long, value_parser = parse_mask)]
+    pub umask: Option<u16>,
+    /// Permission bits, in octal, cleared from files, overriding `--umask`
+    #[arg(long, value_parser = parse_mask)]
+    pub fmask: Option<u16>,
+    /// Permission bits, in octal, cleared from directories, overriding
+    /// `--umask`
+    #[arg(long, value_parser = parse_mask)]
+    pub dmask: Option<u16>,
+    /// Let users other than the one mounting access the mount (needs
+    /// `user_allow_other` in `/etc/fuse.conf` unless mounting as root)
+    #[arg(long)]
+    pub allow_other: bool,
+    /// Let the kernel check permissions against the reported modes instead of
+    /// asking the filesystem
+    #[arg(long)]
+    pub default_permissions: bool,
+    /// Seconds the kernel may cache file attributes, or "infinite"
+    #[arg(long, value_parser = parse_timeout)]
+    pub attr_timeout: Option<Duration>,
+    /// Seconds the kernel may cache name lookups, or "infinite"
+    #[arg(long, value_parser = parse_timeout)]
+    pub entry_timeout: Option<Duration>,
+    /// Seconds the kernel may cache failed lookups, or "infinite"; 0 disables
+    /// negative caching
+    #[arg(long, value_parser = parse_timeout)]
+    pub negative_timeout: Option<Duration>,
+    /// Number of blobs downloaded in the background at once, 0 disables
+    /// prefetching
+    #[arg(long)]
+    pub prefetch_concurrency: Option<usize>,
+    /// Maximum number of sibling blobs queued each time prefetching triggers
+    #[arg(long)]
+    pub prefetch_limit: Option<usize>,
+    /// Access that triggers prefetching of the sibling blobs
+    #[arg(long, value_enum)]
+    pub prefetch_on: Option<PrefetchTrigger>,
+    /// Sparse checkout profile file, in git sparse-checkout cone format
+    #[arg(long)]
+    pub sparse_profile: Option<PathBuf>,
+    /// Directory to expose, relative to the repository root (repeatable)
+    #[arg(long = "sparse-include", value_name = "DIR")]
+    pub sparse_include: Vec<String>,
+    /// Directory to hide, relative to the repository root (repeatable)
+    #[arg(long = "sparse-exclude", value_name = "DIR")]
+    pub sparse_exclude: Vec<String>,
+    /// Show directories outside of the sparse profile as empty directories
+    /// instead of hiding them
+    #[arg(long)]
+    pub sparse_stubs: bool,
+    /// Serve metrics in Prometheus text format on this address, e.g.
+    /// `127.0.0.1:9100`
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
     /// Operation to take
     #[command(subcommand)]
     pub command: Commands,
@@ -37,6 +195,15 @@ pub enum Commands {
         /// Mandatory field
         /// repo name
         target: String,
+        /// Mount options, comma separated as for `mount -o`, e.g.
+        /// `allow_other,noexec,uid=1000`
+        #[arg(
+            short = 'o',
+            long = "options",
+            value_delimiter = ',',
+            value_name = "OPTION"
+        )]
+        options: Vec<String>,
     },
     /// disconnect a repository
     Disconnect {
@@ -44,11 +211,298 @@ pub enum Commands {
         /// repo name
         target: String,
     },
+    /// inspect and maintain the cache in `--cache-dir`, whether or not a
+    /// repository is mounted
+    Cache {
+        /// Cache operation
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+/// Cache maintenance operations
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum CacheCommands {
+    /// show the size, entry count and hit ratio of the cache
+    Stats,
+    /// remove least recently used blobs
+    Prune {
+        /// Shrink the cache to this size, e.g. `512M` or `10G`
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+        /// Remove blobs unused for this long, e.g. `12h` or `7d`
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<Duration>,
+    },
+    /// re-hash every blob against its object id and remove corrupted ones
+    Verify,
+    /// remove cached content
+    Clear {
+        /// Only remove the tree snapshots of this repo and the blobs no other
+        /// repo refers to
+        #[arg(long)]
+        repo: Option<String>,
+    },
+}
+
+/// Access patterns which trigger prefetching of the blobs in a directory
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefetchTrigger {
+    /// Never prefetch
+    None,
+    /// Prefetch the siblings of an opened file
+    Open,
+    /// Prefetch the files of a listed directory
+    Readdir,
+    /// Prefetch on both open and readdir
+    Both,
+}
+
+impl PrefetchTrigger {
+    /// Whether opening a file triggers prefetching
+    pub fn on_open(self) -> bool {
+        matches!(self, PrefetchTrigger::Open | PrefetchTrigger::Both)
+    }
+
+    /// Whether listing a directory triggers prefetching
+    pub fn on_readdir(self) -> bool {
+        matches!(self, PrefetchTrigger::Readdir | PrefetchTrigger::Both)
+    }
+}
+
+/// Formats of the log lines
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Rotation periods of the log file
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// A new file every minute
+    Minutely,
+    /// A new file every hour
+    Hourly,
+    /// A new file every day
+    #[default]
+    Daily,
+    /// A single file growing forever
+    Never,
+}
+
+/// HTTP versions spoken with the Mega server
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/2 if the server speaks it, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    /// A pool of HTTP/1.1 connections
+    Http1,
+    /// A single HTTP/2 connection multiplexing every request
+    Http2,
+}
+
+/// Compression of the responses of the Mega server
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// zstd, or gzip if the server prefers it
+    #[default]
+    Auto,
+    /// gzip only
+    Gzip,
+    /// zstd only
+    Zstd,
+    /// Uncompressed responses, for fast local links
+    None,
 }
 
 /// Export parse() to main
 pub fn parse() -> Args {
-    Args::parse()
+    let argv: Vec<String> = std::env::args().collect();
+    let is_mount_helper = argv
+        .first()
+        .and_then(|argv0| Path::new(argv0).file_name())
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(MOUNT_HELPER_PREFIX));
+    if !is_mount_helper {
+        return Args::parse();
+    }
+    match mount_helper_args(&argv) {
+        Ok(Some(args)) => Args::parse_from(args),
+        // Fake mount: the invocation is valid, nothing more to do
+        Ok(None) => std::process::exit(0),
+        Err(err) => {
+            eprintln!("{}: {}", argv[0], err);
+            // Incorrect invocation, as defined by mount(8)
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Translate a `mount(8)` helper invocation, `<repo>[@<host>:<port>]
+/// <mount point> [-o options] [-t type] [-N namespace] [-f] [-n] [-s] [-v]`,
+/// into the regular command line, or `None` for a fake mount (`-f`).
+/// Options named after a long option of `Args` (with `_` for `-`) set it,
+/// the others are mount options of `connect`.
+pub fn mount_helper_args(argv: &[String]) -> Result<Option<Vec<String>>, String> {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut fake = false;
+    let mut argv_iter = argv.iter().skip(1);
+    while let Some(arg) = argv_iter.next() {
+        match arg.as_str() {
+            "-o" => {
+                let list = argv_iter.next().ok_or("missing options after -o")?;
+                options.extend(list.split(',').map(str::to_owned));
+            }
+            arg if arg.starts_with("-o") => options.extend(arg[2..].split(',').map(str::to_owned)),
+            // The type is known, and mount(8) already switched namespaces
+            "-t" | "-N" => {
+                argv_iter
+                    .next()
+                    .ok_or_else(|| format!("missing value after {}", arg))?;
+            }
+            arg if arg.starts_with("-t") || arg.starts_with("-N") => {}
+            "-f" => fake = true,
+            // No mtab to skip, sloppy and verbose modes change nothing
+            "-n" | "-s" | "-v" => {}
+            arg if arg.starts_with('-') => return Err(format!("unknown flag {}", arg)),
+            arg => positional.push(arg),
+        }
+    }
+    let [source, mount_point] = positional.as_slice() else {
+        return Err("usage: <repo>[@<host>:<port>] <mount point> [-o options]".to_owned());
+    };
+
+    let mut args = vec![
+        argv[0].clone(),
+        "--background".to_owned(),
+        format!("--mount-point={}", mount_point),
+    ];
+    let (repo, server) = match source.rsplit_once('@') {
+        Some((repo, server)) => (repo, Some(server)),
+        None => (*source, None),
+    };
+    if let Some(server) = server {
+        let (host, port) = server
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <host>:<port> in {}", source))?;
+        // IPv6 addresses come in brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        args.push(format!("--mega-host={}", host));
+        args.push(format!("--mega-port={}", port));
+    }
+
+    // Long options of `Args`, and whether they take a value
+    let long_options: HashMap<String, bool> = Args::command()
+        .get_arguments()
+        .filter_map(|arg| Some((arg.get_long()?.to_owned(), arg.get_action().takes_values())))
+        .collect();
+    let mut mount_options = Vec::new();
+    for option in options.into_iter().filter(|option| !option.is_empty()) {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option.as_str(), None),
+        };
+        if let Some((_, implied)) = USER_MOUNT_OPTIONS.iter().find(|(name, _)| *name == key) {
+            mount_options.extend(implied.iter().map(|option| option.to_string()));
+            continue;
+        }
+        let long = key.replace('_', "-");
+        match (long_options.get(&long), value) {
+            (Some(true), Some(value)) => {
+                // The last value wins, clap rejects repeated options
+                let prefix = format!("--{}=", long);
+                args.retain(|arg| !arg.starts_with(&prefix));
+                args.push(format!("{}{}", prefix, value));
+            }
+            (Some(false), None) => args.push(format!("--{}", long)),
+            _ if IGNORED_MOUNT_OPTIONS.contains(&key) => {}
+            _ if key.starts_with("x-") || key == "comment" => {}
+            _ => mount_options.push(option),
+        }
+    }
+
+    args.push("connect".to_owned());
+    args.push(repo.to_owned());
+    if !mount_options.is_empty() {
+        args.push("-o".to_owned());
+        args.push(mount_options.join(","));
+    }
+    Ok((!fake).then_some(args))
+}
+
+/// Parse a size in bytes with an optional binary unit suffix: `K`, `M`, `G`
+/// or `T`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, shift) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size unit `{}`", unit)),
+            };
+            (&value[..index], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = number.parse().map_err(|err| format!("{}", err))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "size too large".to_owned())
+}
+
+/// Parse an age with an optional unit suffix: `s`, `m`, `h` or `d`, seconds
+/// without a suffix.
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&value[..index], unit),
+        _ => (value, 's'),
+    };
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(format!("unknown age unit `{}`", unit)),
+    };
+    let number: u64 = number.parse().map_err(|err| format!("{}", err))?;
+    Ok(Duration::from_secs(number.saturating_mul(secs)))
+}
+
+/// Parse a permission mask given in octal, e.g. `022`.
+pub fn parse_mask(value: &str) -> Result<u16, String> {
+    let mask = u16::from_str_radix(value.trim(), 8).map_err(|err| err.to_string())?;
+    if mask > 0o777 {
+        return Err(format!("mask {:o} exceeds 777", mask));
+    }
+    Ok(mask)
+}
+
+/// Parse a timeout given in (fractional) seconds, or "infinite" for a
+/// revision that never changes.
+pub fn parse_timeout(value: &str) -> Result<Duration, String> {
+    if value.eq_ignore_ascii_case("infinite") {
+        return Ok(INFINITE_TIMEOUT);
+    }
+    value
+        .parse::<f64>()
+        .map_err(|err| err.to_string())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).map_err(|err| err.to_string()))
+        .map(|timeout| timeout.min(INFINITE_TIMEOUT))
 }
 
 #[cfg(test)]
@@ -69,7 +523,266 @@ mod tests {
             args.command,
             Commands::Connect {
                 target: input[12].to_string(),
+                options: Vec::new(),
+            }
+        );
+        assert!(!args.direct_io);
+        assert!(!args.access_log);
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("1"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_timeout("0.5"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_timeout("infinite"), Ok(INFINITE_TIMEOUT));
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("soon").is_err());
+
+        let args = Args::parse_from([
+            "fuse",
+            "--attr-timeout",
+            "infinite",
+            "--negative-timeout",
+            "30",
+            "connect",
+            "mega-fuse",
+        ]);
+        assert_eq!(args.attr_timeout, Some(INFINITE_TIMEOUT));
+        assert_eq!(args.entry_timeout, None);
+        assert_eq!(args.negative_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(args.request_timeout, None);
+    }
+
+    #[test]
+    fn test_cli_parsing_prefetch() {
+        let args = Args::parse_from([
+            "fuse",
+            "--prefetch-concurrency",
+            "8",
+            "--prefetch-on",
+            "readdir",
+            "connect",
+            "mega-fuse",
+        ]);
+        assert_eq!(args.prefetch_concurrency, Some(8));
+        assert_eq!(args.prefetch_limit, None);
+        assert_eq!(args.prefetch_on, Some(PrefetchTrigger::Readdir));
+        assert!(PrefetchTrigger::Readdir.on_readdir());
+        assert!(!PrefetchTrigger::Readdir.on_open());
+    }
+
+    #[test]
+    fn test_cli_parsing_sparse() {
+        let args = Args::parse_from([
+            "fuse",
+            "--sparse-include",
+            "src",
+            "--sparse-include",
+            "docs",
+            "--sparse-exclude",
+            "src/tests",
+            "connect",
+            "mega-fuse",
+        ]);
+        assert_eq!(args.sparse_include, vec!["src", "docs"]);
+        assert_eq!(args.sparse_exclude, vec!["src/tests"]);
+        assert!(!args.sparse_stubs);
+    }
+
+    #[test]
+    fn test_cli_parsing_ownership() {
+        let args = Args::parse_from([
+            "fuse",
+            "--uid",
+            "1500",
+            "--gid",
+            "1500",
+            "--umask",
+            "022",
+            "--fmask",
+            "0133",
+            "--allow-other",
+            "--default-permissions",
+            "connect",
+            "mega",
+        ]);
+        assert_eq!((args.uid, args.gid), (Some(1500), Some(1500)));
+        assert_eq!(args.umask, Some(0o022));
+        assert_eq!(args.fmask, Some(0o133));
+        assert_eq!(args.dmask, None);
+        assert!(args.allow_other && args.default_permissions);
+        assert!(parse_mask("8").is_err());
+        assert!(parse_mask("1777").is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_mount_options() {
+        let args = Args::parse_from([
+            "fuse",
+            "connect",
+            "mega",
+            "-o",
+            "allow_other,noexec",
+            "-o",
+            "fsname=mega",
+        ]);
+        assert_eq!(
+            args.command,
+            Commands::Connect {
+                target: "mega".to_owned(),
+                options: vec![
+                    "allow_other".to_owned(),
+                    "noexec".to_owned(),
+                    "fsname=mega".to_owned()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_mount_helper_args() {
+        let argv: Vec<String> = [
+            "/sbin/mount.megafuse",
+            "third-party/mega@[::1]:8000",
+            "/mnt/mega",
+            "-n",
+            "-t",
+            "fuse.megafuse",
+            "-N",
+            "1234",
+            "-o",
+            "rw,_netdev,cache_dir=/var/cache/mega,offline,allow_other,uid=1000,noexec,x-systemd.automount",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        assert!(args.background && args.offline && args.allow_other);
+        assert_eq!(args.mount_point, Some(PathBuf::from("/mnt/mega")));
+        assert_eq!(args.cache_dir, Some(PathBuf::from("/var/cache/mega")));
+        assert_eq!(args.mega_host.as_deref(), Some("::1"));
+        assert_eq!(args.mega_port, Some(8000));
+        assert_eq!(args.uid, Some(1000));
+        assert_eq!(
+            args.command,
+            Commands::Connect {
+                target: "third-party/mega".to_owned(),
+                options: vec!["noexec".to_owned()],
+            }
+        );
+
+        let argv = ["mount.megafuse", "mega", "/mnt/mega", "-oro"].map(str::to_owned);
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        assert!(args.mega_host.is_none());
+        assert!(mount_helper_args(&argv[..2]).is_err());
+        let argv = ["mount.megafuse", "mega", "/mnt/mega", "-ouid=1,uid=2"].map(str::to_owned);
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        assert_eq!(args.uid, Some(2));
+        // Users mounting imply restrictions, unless overridden
+        let argv = ["mount.megafuse", "mega", "/mnt/mega", "-ouser=alice,exec"].map(str::to_owned);
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        let Commands::Connect { options, .. } = args.command else {
+            panic!("not connecting");
+        };
+        assert_eq!(options, ["noexec", "nosuid", "nodev", "exec"]);
+        let fake = ["mount.megafuse", "mega", "/mnt/mega", "-f"].map(str::to_owned);
+        assert_eq!(mount_helper_args(&fake), Ok(None));
+        assert!(
+            mount_helper_args(&["mount.megafuse", "mega", "/mnt", "-t"].map(str::to_owned))
+                .is_err()
+        );
+        assert!(
+            mount_helper_args(&["mount.megafuse", "mega@host", "/mnt"].map(str::to_owned)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_log() {
+        let args = Args::parse_from([
+            "fuse",
+            "--config",
+            "path/to/fuse.toml",
+            "--log-level",
+            "info,fuse::core=trace",
+            "--log-format",
+            "json",
+            "--log-rotation",
+            "hourly",
+            "--log-max-files",
+            "24",
+            "--trace-file",
+            "path/to/trace.json",
+            "connect",
+            "mega",
+        ]);
+        assert_eq!(args.config, Some(PathBuf::from("path/to/fuse.toml")));
+        assert_eq!(args.log_level.as_deref(), Some("info,fuse::core=trace"));
+        assert_eq!(args.log_format, Some(LogFormat::Json));
+        assert_eq!(args.log_rotation, Some(LogRotation::Hourly));
+        assert_eq!(args.log_max_files, Some(24));
+        assert_eq!(args.trace_file, Some(PathBuf::from("path/to/trace.json")));
+    }
+
+    #[test]
+    fn test_cli_parsing_cache() {
+        let args = Args::parse_from([
+            "fuse",
+            "--cache-dir",
+            "path/to/cache",
+            "cache",
+            "prune",
+            "--max-size",
+            "10G",
+            "--older-than",
+            "7d",
+        ]);
+        assert_eq!(
+            args.command,
+            Commands::Cache {
+                command: CacheCommands::Prune {
+                    max_size: Some(10 << 30),
+                    older_than: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+                }
             }
         );
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert!(parse_size("1X").is_err());
+        assert_eq!(parse_age("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_age("1w").is_err());
+
+        let args = Args::parse_from(["fuse", "cache", "clear", "--repo", "mega"]);
+        assert_eq!(
+            args.command,
+            Commands::Cache {
+                command: CacheCommands::Clear {
+                    repo: Some("mega".to_owned())
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_direct_io() {
+        let args = Args::parse_from(["fuse", "--direct-io", "connect", "mega-fuse"]);
+        assert!(args.direct_io);
+        assert!(!args.git_view);
+        assert!(args.http_version.is_none());
+
+        let args = Args::parse_from(["fuse", "--http-version", "http1", "connect", "mega"]);
+        assert_eq!(args.http_version, Some(HttpVersion::Http1));
+        assert!(args.compression.is_none());
+
+        let args = Args::parse_from(["fuse", "--compression", "none", "connect", "mega"]);
+        assert_eq!(args.compression, Some(Compression::None));
+        assert!(args.metrics_addr.is_none());
+
+        let args = Args::parse_from([
+            "fuse",
+            "--metrics-addr",
+            "127.0.0.1:9100",
+            "connect",
+            "mega",
+        ]);
+        assert_eq!(args.metrics_addr, Some(([127, 0, 0, 1], 9100).into()));
     }
 }
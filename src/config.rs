@@ -6,11 +6,44 @@
 //! 3. `ValidatedConfig` is generated from `Config`, with all necessary fields
 //!    checked to be valid to get `core` to work.
 //! Configuration preparation before `core` starts.
-use std::{convert::From, path::PathBuf};
+use std::{
+    convert::From,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
+use fuser::MountOption;
+use serde::{de, Deserialize, Deserializer};
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 
-use crate::cli::Args;
+use crate::{
+    cli::{
+        parse_mask, parse_timeout, Args, Commands, Compression, HttpVersion, LogFormat,
+        LogRotation, PrefetchTrigger,
+    },
+    core::sparse::SparseProfile,
+};
+
+const DEFAULT_ATTR_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_ENTRY_TIMEOUT: Duration = Duration::from_secs(1);
+// Negative lookups are not cached unless asked for
+const DEFAULT_NEGATIVE_TIMEOUT: Duration = Duration::ZERO;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_PREFETCH_CONCURRENCY: usize = 4;
+const DEFAULT_PREFETCH_LIMIT: usize = 64;
+const DEFAULT_PREFETCH_TRIGGER: PrefetchTrigger = PrefetchTrigger::Open;
+const DEFAULT_LOG_FILTER: &str = "info";
+const DEFAULT_FSNAME: &str = "MegaFUSE";
+const DEFAULT_SUBTYPE: &str = "megafuse";
+const DEFAULT_API_BASE: &str = "/api";
+const DEFAULT_API_VERSION: &str = "v1";
+const DEFAULT_REPO_ROOT: &str = "/projects";
 
 /// Configurations are read from config files and then can be override by the
 /// supplied fields from command line. This config is a super set of `Args` read
@@ -23,13 +56,167 @@ pub struct Config {
     cache_dir: Option<PathBuf>,
     /// Log directory
     log_dir: Option<PathBuf>,
+    /// Log filter directives
+    log_level: Option<String>,
+    /// Format of the log lines
+    log_format: Option<LogFormat>,
+    /// How often the log file is rotated
+    log_rotation: Option<LogRotation>,
+    /// Number of log files kept
+    log_max_files: Option<usize>,
+    /// Chrome trace file
+    trace_file: Option<PathBuf>,
+    /// Record file accesses to the log directory
+    access_log: bool,
     /// Mega server Host
     mega_host: Option<String>,
     /// Mega server Port
     mega_port: Option<u16>,
+    /// Path the Mega API is served under
+    api_base: Option<String>,
+    /// Version of the Mega API
+    api_version: Option<String>,
+    /// Directory of the monorepo holding the repositories
+    repo_root: Option<String>,
+    /// HTTP version spoken with the Mega server
+    http_version: Option<HttpVersion>,
+    /// Compression asked of the Mega server
+    compression: Option<Compression>,
+    /// How long to wait for a connection to the Mega server
+    connect_timeout: Option<Duration>,
+    /// How long to wait for the server to start answering
+    first_byte_timeout: Option<Duration>,
+    /// How long a whole request may take
+    request_timeout: Option<Duration>,
+    /// Mount the last known tree without contacting the Mega server
+    offline: bool,
+    /// Open files with `FOPEN_DIRECT_IO` instead of using the page cache
+    direct_io: bool,
+    /// Expose a `.git` directory presenting the mounted commit
+    git_view: bool,
+    /// Owner of every file
+    uid: Option<u32>,
+    /// Group of every file
+    gid: Option<u32>,
+    /// Permission bits cleared from every file and directory
+    umask: Option<u16>,
+    /// Permission bits cleared from files
+    fmask: Option<u16>,
+    /// Permission bits cleared from directories
+    dmask: Option<u16>,
+    /// Let other users access the mount
+    allow_other: bool,
+    /// Let the kernel check permissions
+    default_permissions: bool,
+    /// `-o` mount options not mapped to another field yet
+    mount_options: Vec<String>,
+    /// How long the kernel may cache file attributes
+    attr_timeout: Option<Duration>,
+    /// How long the kernel may cache name lookups
+    entry_timeout: Option<Duration>,
+    /// How long the kernel may cache failed lookups
+    negative_timeout: Option<Duration>,
+    /// Number of blobs downloaded in the background at once
+    prefetch_concurrency: Option<usize>,
+    /// Maximum number of sibling blobs queued per trigger
+    prefetch_limit: Option<usize>,
+    /// Access that triggers prefetching
+    prefetch_on: Option<PrefetchTrigger>,
+    /// Sparse checkout profile file
+    sparse_profile: Option<PathBuf>,
+    /// Directories to expose
+    sparse_include: Vec<String>,
+    /// Directories to hide
+    sparse_exclude: Vec<String>,
+    /// Show directories outside of the profile as empty stubs
+    sparse_stubs: bool,
+    /// Address of the metrics endpoint
+    metrics_addr: Option<SocketAddr>,
 }
 
 impl Config {
+    /// Fill the fields missing from the command line with the ones of a
+    /// configuration file.
+    pub fn merge(&mut self, file: ConfigFile) {
+        self.mount_point = self.mount_point.take().or(file.mount_point);
+        self.cache_dir = self.cache_dir.take().or(file.cache_dir);
+        self.log_dir = self.log_dir.take().or(file.log_dir);
+        self.log_level = self.log_level.take().or(file.log_level);
+        self.log_format = self.log_format.or(file.log_format);
+        self.log_rotation = self.log_rotation.or(file.log_rotation);
+        self.log_max_files = self.log_max_files.or(file.log_max_files);
+        self.trace_file = self.trace_file.take().or(file.trace_file);
+        self.access_log |= file.access_log.unwrap_or_default();
+        self.mega_host = self.mega_host.take().or(file.mega_host);
+        self.mega_port = self.mega_port.or(file.mega_port);
+        self.api_base = self.api_base.take().or(file.api_base);
+        self.api_version = self.api_version.take().or(file.api_version);
+        self.repo_root = self.repo_root.take().or(file.repo_root);
+        self.http_version = self.http_version.or(file.http_version);
+        self.compression = self.compression.or(file.compression);
+        self.connect_timeout = self.connect_timeout.or(file.connect_timeout);
+        self.first_byte_timeout = self.first_byte_timeout.or(file.first_byte_timeout);
+        self.request_timeout = self.request_timeout.or(file.request_timeout);
+        self.offline |= file.offline.unwrap_or_default();
+        self.direct_io |= file.direct_io.unwrap_or_default();
+        self.git_view |= file.git_view.unwrap_or_default();
+        self.uid = self.uid.or(file.uid);
+        self.gid = self.gid.or(file.gid);
+        self.umask = self.umask.or(file.umask);
+        self.fmask = self.fmask.or(file.fmask);
+        self.dmask = self.dmask.or(file.dmask);
+        self.allow_other |= file.allow_other.unwrap_or_default();
+        self.default_permissions |= file.default_permissions.unwrap_or_default();
+        // The file's options go first, so the command line's override them
+        let given = std::mem::take(&mut self.mount_options);
+        self.add_mount_options(file.options);
+        self.mount_options.extend(given);
+        self.attr_timeout = self.attr_timeout.or(file.attr_timeout);
+        self.entry_timeout = self.entry_timeout.or(file.entry_timeout);
+        self.negative_timeout = self.negative_timeout.or(file.negative_timeout);
+        self.prefetch_concurrency = self.prefetch_concurrency.or(file.prefetch_concurrency);
+        self.prefetch_limit = self.prefetch_limit.or(file.prefetch_limit);
+        self.prefetch_on = self.prefetch_on.or(file.prefetch_on);
+        self.sparse_profile = self.sparse_profile.take().or(file.sparse_profile);
+        if self.sparse_include.is_empty() {
+            self.sparse_include = file.sparse_include;
+        }
+        if self.sparse_exclude.is_empty() {
+            self.sparse_exclude = file.sparse_exclude;
+        }
+        self.sparse_stubs |= file.sparse_stubs.unwrap_or_default();
+        self.metrics_addr = self.metrics_addr.or(file.metrics_addr);
+    }
+
+    /// Validate the cache directory alone, for the operations which do not
+    /// mount anything.
+    pub(crate) fn validated_cache_dir(&mut self) -> Result<PathBuf, ()> {
+        self.validate_cache_dir()
+    }
+
+    /// Validate the logging options, needed before anything else runs. Logs
+    /// go to stdout without a log directory.
+    pub(crate) fn validated_log(&self) -> Result<LogSettings, ()> {
+        let filter = self
+            .log_level
+            .clone()
+            .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_owned());
+        EnvFilter::try_new(&filter).map_err(|_| ())?;
+        let dir = match &self.log_dir {
+            Some(dir) if dir.is_dir() => Some(dir.clone()),
+            Some(_) => return Err(()),
+            None => None,
+        };
+        Ok(LogSettings {
+            filter,
+            format: self.log_format.unwrap_or_default(),
+            rotation: self.log_rotation.unwrap_or_default(),
+            max_files: self.log_max_files,
+            dir,
+            trace_file: self.trace_file.clone(),
+        })
+    }
+
     fn validate_mount_point(&mut self) -> Result<PathBuf, ()> {
         if self.mount_point.is_some() && self.mount_point.as_ref().unwrap().is_dir() {
             Ok(self.mount_point.take().unwrap())
@@ -46,29 +233,477 @@ impl Config {
         }
     }
 
-    fn validate_log_dir(&mut self) -> Result<PathBuf, ()> {
-        if self.log_dir.is_some() && self.log_dir.as_ref().unwrap().is_dir() {
-            Ok(self.log_dir.take().unwrap())
-        } else {
-            Err(())
+    /// Logs go to stdout without a log directory, only the access log needs
+    /// one.
+    fn validate_log_dir(&mut self) -> Result<Option<PathBuf>, ()> {
+        match self.log_dir.take() {
+            Some(dir) if dir.is_dir() => Ok(Some(dir)),
+            Some(dir) => {
+                error!("Log directory {} does not exist", dir.display());
+                Err(())
+            }
+            None if self.access_log => {
+                error!("The access log needs a log directory");
+                Err(())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn validate_timeouts(&mut self) -> Result<(Duration, Duration, Duration), ()> {
+        Ok((
+            self.attr_timeout.unwrap_or(DEFAULT_ATTR_TIMEOUT),
+            self.entry_timeout.unwrap_or(DEFAULT_ENTRY_TIMEOUT),
+            self.negative_timeout.unwrap_or(DEFAULT_NEGATIVE_TIMEOUT),
+        ))
+    }
+
+    fn validate_request_timeouts(&mut self) -> Result<RequestTimeouts, ()> {
+        let timeouts = RequestTimeouts {
+            connect: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            first_byte: self
+                .first_byte_timeout
+                .unwrap_or(DEFAULT_FIRST_BYTE_TIMEOUT),
+            total: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        };
+        // Every request would fail
+        if [timeouts.connect, timeouts.first_byte, timeouts.total].contains(&Duration::ZERO) {
+            error!("Request timeouts must be positive");
+            return Err(());
+        }
+        Ok(timeouts)
+    }
+
+    /// Record a list of `-o` mount options. Options with a field of their own
+    /// fill it unless already set by a long option; within the list the last
+    /// occurrence wins, as for the other mount options.
+    fn add_mount_options(&mut self, options: impl IntoIterator<Item = String>) {
+        let given = (
+            self.uid.take(),
+            self.gid.take(),
+            self.umask.take(),
+            self.fmask.take(),
+            self.dmask.take(),
+        );
+        for option in options {
+            self.add_mount_option(option);
+        }
+        self.uid = given.0.or(self.uid);
+        self.gid = given.1.or(self.gid);
+        self.umask = given.2.or(self.umask);
+        self.fmask = given.3.or(self.fmask);
+        self.dmask = given.4.or(self.dmask);
+    }
+
+    fn add_mount_option(&mut self, option: String) {
+        let parsed = match option.split_once('=') {
+            Some(("uid", uid)) => uid.parse().ok().map(|uid| self.uid = Some(uid)),
+            Some(("gid", gid)) => gid.parse().ok().map(|gid| self.gid = Some(gid)),
+            Some(("umask", mask)) => parse_mask(mask).ok().map(|mask| self.umask = Some(mask)),
+            Some(("fmask", mask)) => parse_mask(mask).ok().map(|mask| self.fmask = Some(mask)),
+            Some(("dmask", mask)) => parse_mask(mask).ok().map(|mask| self.dmask = Some(mask)),
+            _ => None,
+        };
+        // Invalid values are left to be reported by the validation
+        if parsed.is_none() {
+            self.mount_options.push(option);
+        }
+    }
+
+    fn validate_mount_options(&mut self) -> Result<Vec<MountOption>, ()> {
+        let mut options = vec![
+            MountOption::RO,
+            MountOption::FSName(DEFAULT_FSNAME.to_owned()),
+            MountOption::Subtype(DEFAULT_SUBTYPE.to_owned()),
+            MountOption::NoDev,
+            MountOption::NoSuid,
+        ];
+        let flags = [
+            (self.allow_other, "allow_other"),
+            (self.default_permissions, "default_permissions"),
+        ];
+        let given = flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, option)| option.to_string());
+        for option in given.chain(self.mount_options.drain(..)) {
+            let option = match option.split_once('=') {
+                None => match option.as_str() {
+                    "ro" => MountOption::RO,
+                    "allow_other" => MountOption::AllowOther,
+                    "allow_root" => MountOption::AllowRoot,
+                    "auto_unmount" => MountOption::AutoUnmount,
+                    "default_permissions" => MountOption::DefaultPermissions,
+                    "dev" => MountOption::Dev,
+                    "nodev" => MountOption::NoDev,
+                    "suid" => MountOption::Suid,
+                    "nosuid" => MountOption::NoSuid,
+                    "exec" => MountOption::Exec,
+                    "noexec" => MountOption::NoExec,
+                    "atime" => MountOption::Atime,
+                    "noatime" => MountOption::NoAtime,
+                    "sync" => MountOption::Sync,
+                    "async" => MountOption::Async,
+                    "dirsync" => MountOption::DirSync,
+                    "rw" => {
+                        error!("Mount option `rw`: the file system is read-only");
+                        return Err(());
+                    }
+                    _ => {
+                        error!("Unknown mount option `{}`", option);
+                        return Err(());
+                    }
+                },
+                Some(("fsname", name)) if !name.is_empty() => MountOption::FSName(name.to_owned()),
+                Some(("subtype", name)) if !name.is_empty() => {
+                    MountOption::Subtype(name.to_owned())
+                }
+                Some(_) => {
+                    error!("Unknown or invalid mount option `{}`", option);
+                    return Err(());
+                }
+            };
+            // Like mount(8), a later option overrides an earlier conflicting one
+            options.retain(|existing| !overrides(&option, existing));
+            options.push(option);
         }
+        Ok(options)
+    }
+
+    fn validate_ownership(&mut self) -> Result<Ownership, ()> {
+        // SAFETY: both calls always succeed
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let umask = self.umask.unwrap_or(0);
+        Ok(Ownership {
+            uid: self.uid.unwrap_or(uid),
+            gid: self.gid.unwrap_or(gid),
+            fmask: self.fmask.unwrap_or(umask),
+            dmask: self.dmask.unwrap_or(umask),
+        })
+    }
+
+    /// Normalize the API paths: absolute, without trailing slashes.
+    fn validate_api(&mut self) -> Result<ApiSettings, ()> {
+        let absolute = |path: Option<String>, default: &str| {
+            let path = path.unwrap_or_else(|| default.to_owned());
+            format!("/{}", path.trim_matches('/'))
+        };
+        let version = self
+            .api_version
+            .take()
+            .unwrap_or_else(|| DEFAULT_API_VERSION.to_owned());
+        let version = version.trim_matches('/');
+        if version.is_empty() || version.contains('/') {
+            error!("Invalid API version: {:?}", version);
+            return Err(());
+        }
+        Ok(ApiSettings {
+            // An API served at the root of the server has no base path
+            base_path: absolute(self.api_base.take(), DEFAULT_API_BASE)
+                .trim_end_matches('/')
+                .to_owned(),
+            version: version.to_owned(),
+            repo_root: absolute(self.repo_root.take(), DEFAULT_REPO_ROOT),
+        })
+    }
+
+    fn validate_prefetch(&mut self) -> Result<PrefetchSettings, ()> {
+        let concurrency = self
+            .prefetch_concurrency
+            .unwrap_or(DEFAULT_PREFETCH_CONCURRENCY);
+        let trigger = match concurrency {
+            0 => PrefetchTrigger::None,
+            _ => self.prefetch_on.unwrap_or(DEFAULT_PREFETCH_TRIGGER),
+        };
+        Ok(PrefetchSettings {
+            concurrency,
+            limit: self.prefetch_limit.unwrap_or(DEFAULT_PREFETCH_LIMIT),
+            trigger,
+        })
+    }
+
+    fn validate_sparse(&mut self) -> Result<SparseProfile, ()> {
+        let mut profile = SparseProfile::new(
+            self.sparse_include.drain(..),
+            self.sparse_exclude.drain(..),
+            self.sparse_stubs,
+        );
+        if let Some(path) = self.sparse_profile.take() {
+            let file = SparseProfile::from_file(&path, self.sparse_stubs).map_err(|err| {
+                error!("Invalid sparse profile {}: {}", path.display(), err);
+            })?;
+            profile.extend(file);
+        }
+        Ok(profile)
     }
 
     fn validate_mega_url(&mut self) -> Result<String, ()> {
-        let host = self.mega_host.take().unwrap();
-        let port = self.mega_port.unwrap();
-        Ok(format!("{}:{}", host, port))
+        match (self.mega_host.take(), self.mega_port) {
+            // IPv6 addresses need their brackets back to be dialed
+            (Some(host), Some(port)) if host.contains(':') && !host.starts_with('[') => {
+                Ok(format!("[{}]:{}", host, port))
+            }
+            (Some(host), Some(port)) => Ok(format!("{}:{}", host, port)),
+            // The server is never dialed when offline
+            _ if self.offline => Ok(String::new()),
+            _ => Err(()),
+        }
     }
 }
 
 impl From<&Args> for Config {
     fn from(args: &Args) -> Self {
-        Config {
+        let mut config = Config {
             mount_point: args.mount_point.clone(),
             cache_dir: args.cache_dir.clone(),
             log_dir: args.log_dir.clone(),
+            // The environment only overrides the configuration file
+            log_level: args.log_level.clone().or_else(|| env::var("RUST_LOG").ok()),
+            log_format: args.log_format,
+            log_rotation: args.log_rotation,
+            log_max_files: args.log_max_files,
+            trace_file: args.trace_file.clone(),
+            access_log: args.access_log,
             mega_host: args.mega_host.clone(),
             mega_port: args.mega_port,
+            api_base: args.api_base.clone(),
+            api_version: args.api_version.clone(),
+            repo_root: args.repo_root.clone(),
+            http_version: args.http_version,
+            compression: args.compression,
+            connect_timeout: args.connect_timeout,
+            first_byte_timeout: args.first_byte_timeout,
+            request_timeout: args.request_timeout,
+            offline: args.offline,
+            direct_io: args.direct_io,
+            git_view: args.git_view,
+            uid: args.uid,
+            gid: args.gid,
+            umask: args.umask,
+            fmask: args.fmask,
+            dmask: args.dmask,
+            allow_other: args.allow_other,
+            default_permissions: args.default_permissions,
+            mount_options: Vec::new(),
+            attr_timeout: args.attr_timeout,
+            entry_timeout: args.entry_timeout,
+            negative_timeout: args.negative_timeout,
+            prefetch_concurrency: args.prefetch_concurrency,
+            prefetch_limit: args.prefetch_limit,
+            prefetch_on: args.prefetch_on,
+            sparse_profile: args.sparse_profile.clone(),
+            sparse_include: args.sparse_include.clone(),
+            sparse_exclude: args.sparse_exclude.clone(),
+            sparse_stubs: args.sparse_stubs,
+            metrics_addr: args.metrics_addr,
+        };
+        if let Commands::Connect { options, .. } = &args.command {
+            config.add_mount_options(options.iter().cloned());
+        }
+        config
+    }
+}
+
+/// Content of a configuration file, in TOML with the long option names of
+/// `Args` as keys, e.g. `cache-dir = "/var/cache/mega"`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    mount_point: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    log_dir: Option<PathBuf>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    log_rotation: Option<LogRotation>,
+    log_max_files: Option<usize>,
+    trace_file: Option<PathBuf>,
+    access_log: Option<bool>,
+    mega_host: Option<String>,
+    mega_port: Option<u16>,
+    api_base: Option<String>,
+    api_version: Option<String>,
+    repo_root: Option<String>,
+    http_version: Option<HttpVersion>,
+    compression: Option<Compression>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    connect_timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    first_byte_timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    request_timeout: Option<Duration>,
+    offline: Option<bool>,
+    direct_io: Option<bool>,
+    git_view: Option<bool>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    #[serde(deserialize_with = "deserialize_mask")]
+    umask: Option<u16>,
+    #[serde(deserialize_with = "deserialize_mask")]
+    fmask: Option<u16>,
+    #[serde(deserialize_with = "deserialize_mask")]
+    dmask: Option<u16>,
+    allow_other: Option<bool>,
+    default_permissions: Option<bool>,
+    /// `-o` style mount options
+    options: Vec<String>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    attr_timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    entry_timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_timeout")]
+    negative_timeout: Option<Duration>,
+    prefetch_concurrency: Option<usize>,
+    prefetch_limit: Option<usize>,
+    prefetch_on: Option<PrefetchTrigger>,
+    sparse_profile: Option<PathBuf>,
+    sparse_include: Vec<String>,
+    sparse_exclude: Vec<String>,
+    sparse_stubs: Option<bool>,
+    metrics_addr: Option<SocketAddr>,
+}
+
+impl ConfigFile {
+    /// Read and parse the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Timeouts are given in seconds or as "infinite", like on the command line
+fn deserialize_timeout<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timeout {
+        Seconds(f64),
+        Text(String),
+    }
+    let value = match Option::<Timeout>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Timeout::Seconds(secs)) => secs.to_string(),
+        Some(Timeout::Text(text)) => text,
+    };
+    parse_timeout(&value).map(Some).map_err(de::Error::custom)
+}
+
+/// Whether mount option `new` replaces `existing`: same option, opposite flag
+/// or another value of a named option.
+fn overrides(new: &MountOption, existing: &MountOption) -> bool {
+    use MountOption::*;
+    matches!(
+        (new, existing),
+        (FSName(_), FSName(_))
+            | (Subtype(_), Subtype(_))
+            | (Dev | NoDev, Dev | NoDev)
+            | (Suid | NoSuid, Suid | NoSuid)
+            | (Exec | NoExec, Exec | NoExec)
+            | (Atime | NoAtime, Atime | NoAtime)
+            | (Sync | Async, Sync | Async)
+    ) || new == existing
+}
+
+/// Masks are given as octal strings like on the command line, or as integers
+/// written in octal, e.g. `0o022`
+fn deserialize_mask<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mask {
+        Integer(u16),
+        Octal(String),
+    }
+    let mask = match Option::<Mask>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Mask::Integer(mask)) => mask,
+        Some(Mask::Octal(text)) => parse_mask(&text).map_err(de::Error::custom)?,
+    };
+    if mask > 0o777 {
+        return Err(de::Error::custom(format!("mask {:o} exceeds 777", mask)));
+    }
+    Ok(Some(mask))
+}
+
+/// Where log lines go and which of them are kept
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    /// `EnvFilter` directives
+    pub filter: String,
+    /// Format of the log lines
+    pub format: LogFormat,
+    /// How often the log file is rotated
+    pub rotation: LogRotation,
+    /// Number of log files kept, all of them if unset
+    pub max_files: Option<usize>,
+    /// Directory of the log files, stdout if unset
+    pub dir: Option<PathBuf>,
+    /// Chrome trace file of the spans, if any
+    pub trace_file: Option<PathBuf>,
+}
+
+/// Ownership and permissions reported for the mounted files
+#[derive(Clone, Copy, Debug)]
+pub struct Ownership {
+    /// Owner of every file
+    pub uid: u32,
+    /// Group of every file
+    pub gid: u32,
+    /// Permission bits cleared from files
+    pub fmask: u16,
+    /// Permission bits cleared from directories
+    pub dmask: u16,
+}
+
+/// Heuristics of the blob prefetcher
+#[derive(Clone, Copy, Debug)]
+pub struct PrefetchSettings {
+    /// Number of blobs downloaded in the background at once, 0 disables
+    /// prefetching
+    pub concurrency: usize,
+    /// Maximum number of sibling blobs queued each time prefetching triggers
+    pub limit: usize,
+    /// Access that triggers prefetching
+    pub trigger: PrefetchTrigger,
+}
+
+/// Where the Mega API lives on the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiSettings {
+    /// Path the API is served under, empty or starting with a slash
+    pub base_path: String,
+    /// Version segment following the base path
+    pub version: String,
+    /// Directory of the monorepo holding the repositories
+    pub repo_root: String,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        ApiSettings {
+            base_path: DEFAULT_API_BASE.to_owned(),
+            version: DEFAULT_API_VERSION.to_owned(),
+            repo_root: DEFAULT_REPO_ROOT.to_owned(),
+        }
+    }
+}
+
+/// Limits on the requests to the Mega server. Requests exceeding them fail
+/// with `ETIMEDOUT` instead of blocking the processes reading the mount.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestTimeouts {
+    /// Establishing a connection
+    pub connect: Duration,
+    /// Between sending a request and receiving the head of its response
+    pub first_byte: Duration,
+    /// A whole request, body included
+    pub total: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        RequestTimeouts {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            first_byte: DEFAULT_FIRST_BYTE_TIMEOUT,
+            total: DEFAULT_REQUEST_TIMEOUT,
         }
     }
 }
@@ -80,21 +715,272 @@ pub struct ValidatedConfig {
     pub mount_point: PathBuf,
     /// Cache directory: where the data pulled from remote actually resides
     pub cache_dir: PathBuf,
-    /// Log directory
-    pub log_dir: PathBuf,
+    /// Log directory, logs go to stdout without one
+    pub log_dir: Option<PathBuf>,
+    /// Record file accesses to `access.log` in `log_dir`
+    pub access_log: bool,
     /// Joined by Mega server URL and API version, must be dialed and then check
     /// its response to make sure the server's `object services` are ready
     pub server_url: String,
+    /// Base path and version of the API, and where repositories live
+    pub api: ApiSettings,
+    /// HTTP version spoken with the Mega server
+    pub http_version: HttpVersion,
+    /// Compression asked of the Mega server
+    pub compression: Compression,
+    /// Limits on the requests to the Mega server
+    pub request_timeouts: RequestTimeouts,
+    /// Mount the last known tree without contacting the Mega server
+    pub offline: bool,
+    /// Open files with `FOPEN_DIRECT_IO` instead of using the page cache
+    pub direct_io: bool,
+    /// Expose a `.git` directory presenting the mounted commit as `HEAD`
+    pub git_view: bool,
+    /// Ownership and permissions of the mounted files
+    pub ownership: Ownership,
+    /// Options passed to the kernel when mounting
+    pub mount_options: Vec<MountOption>,
+    /// How long the kernel may cache file attributes
+    pub attr_timeout: Duration,
+    /// How long the kernel may cache name lookups
+    pub entry_timeout: Duration,
+    /// How long the kernel may cache failed lookups, zero disables it
+    pub negative_timeout: Duration,
+    /// Heuristics of the blob prefetcher
+    pub prefetch: PrefetchSettings,
+    /// Parts of the tree exposed by the mount
+    pub sparse: SparseProfile,
+    /// Address of the metrics endpoint, if any
+    pub metrics_addr: Option<SocketAddr>,
 }
 
 impl From<Config> for ValidatedConfig {
     fn from(args: Config) -> Self {
         let mut args = args;
+        // Mount options may set the ownership
+        let mount_options = args.validate_mount_options().unwrap();
+        let (attr_timeout, entry_timeout, negative_timeout) = args.validate_timeouts().unwrap();
         ValidatedConfig {
             mount_point: args.validate_mount_point().unwrap(),
             cache_dir: args.validate_cache_dir().unwrap(),
             log_dir: args.validate_log_dir().unwrap(),
+            access_log: args.access_log,
             server_url: args.validate_mega_url().unwrap(),
+            api: args.validate_api().unwrap(),
+            http_version: args.http_version.unwrap_or_default(),
+            compression: args.compression.unwrap_or_default(),
+            request_timeouts: args.validate_request_timeouts().unwrap(),
+            offline: args.offline,
+            direct_io: args.direct_io,
+            git_view: args.git_view,
+            ownership: args.validate_ownership().unwrap(),
+            mount_options,
+            attr_timeout,
+            entry_timeout,
+            negative_timeout,
+            prefetch: args.validate_prefetch().unwrap(),
+            sparse: args.validate_sparse().unwrap(),
+            metrics_addr: args.metrics_addr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::{mount_helper_args, INFINITE_TIMEOUT};
+
+    #[test]
+    fn test_validate_mega_url() {
+        let argv = ["mount.megafuse", "mega@[::1]:8000", "/mnt/mega"].map(str::to_owned);
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        let url = Config::from(&args).validate_mega_url().unwrap();
+        assert_eq!(
+            url.parse::<SocketAddr>().unwrap(),
+            "[::1]:8000".parse().unwrap()
+        );
+
+        let args = Args::parse_from([
+            "fuse",
+            "--mega-host=127.0.0.1",
+            "--mega-port=8000",
+            "connect",
+            "mega",
+        ]);
+        let url = Config::from(&args).validate_mega_url().unwrap();
+        assert_eq!(
+            url.parse::<SocketAddr>().unwrap(),
+            "127.0.0.1:8000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_config_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            mega-host = "mega.com"
+            mega-port = 8000
+            api-base = "mega/api/"
+            api-version = "v1"
+            http-version = "http2"
+            compression = "gzip"
+            log-level = "debug"
+            log-format = "json"
+            attr-timeout = 2.5
+            entry-timeout = "infinite"
+            request-timeout = 60
+            first-byte-timeout = 0
+            prefetch-on = "both"
+            sparse-include = ["src"]
+            umask = "022"
+            fmask = 0o133
+            options = ["noexec", "uid=1500", "gid=abc"]
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "fuse",
+            "--mega-port",
+            "9000",
+            "--api-version",
+            "/v2/",
+            "connect",
+            "mega",
+            "-o",
+            "exec,uid=1000,fsname=mega",
+        ]);
+        let mut config = Config::from(&args);
+        config.merge(file);
+
+        // The command line takes precedence
+        assert_eq!(config.mega_port, Some(9000));
+        assert_eq!(config.mega_host.as_deref(), Some("mega.com"));
+        assert_eq!(config.attr_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.entry_timeout, Some(INFINITE_TIMEOUT));
+        // A zero timeout would fail every request
+        assert!(config.validate_request_timeouts().is_err());
+        config.first_byte_timeout = None;
+        assert_eq!(
+            config.validate_request_timeouts().unwrap(),
+            RequestTimeouts {
+                total: Duration::from_secs(60),
+                ..RequestTimeouts::default()
+            }
+        );
+        assert_eq!(config.prefetch_on, Some(PrefetchTrigger::Both));
+        assert_eq!(config.sparse_include, vec!["src"]);
+        assert_eq!(config.http_version, Some(HttpVersion::Http2));
+        assert_eq!(config.compression, Some(Compression::Gzip));
+        assert_eq!(
+            config.validate_api().unwrap(),
+            ApiSettings {
+                base_path: "/mega/api".to_owned(),
+                version: "v2".to_owned(),
+                repo_root: "/projects".to_owned(),
+            }
+        );
+        let ownership = config.validate_ownership().unwrap();
+        assert_eq!((ownership.fmask, ownership.dmask), (0o133, 0o022));
+        assert_eq!(ownership.uid, 1000);
+        // `gid=abc` is invalid
+        assert!(config.validate_mount_options().is_err());
+
+        let log = config.validated_log().unwrap();
+        assert_eq!(log.format, LogFormat::Json);
+        assert_eq!(log.rotation, LogRotation::Daily);
+        assert!(log.dir.is_none());
+
+        assert!(toml::from_str::<ConfigFile>("mega-hots = \"mega.com\"").is_err());
+    }
+
+    #[test]
+    fn test_fstab_entry() {
+        // The example of the `cli` module documentation
+        let argv = [
+            "mount.megafuse",
+            "mega@mega.example.com:8000",
+            "/mnt/mega",
+            "-o",
+            "cache_dir=/var/cache/mega,allow_other,_netdev",
+        ]
+        .map(str::to_owned);
+        let args = Args::parse_from(mount_helper_args(&argv).unwrap().unwrap());
+        let mut config = Config::from(&args);
+        assert_eq!(config.validate_log_dir(), Ok(None));
+        assert!(config.validated_log().unwrap().dir.is_none());
+        assert!(config.validate_mount_options().is_ok());
+    }
+
+    #[test]
+    fn test_validate_log_dir() {
+        let args = Args::parse_from(["fuse", "connect", "mega"]);
+        assert_eq!(Config::from(&args).validate_log_dir(), Ok(None));
+        let args = Args::parse_from(["fuse", "--access-log", "connect", "mega"]);
+        assert!(Config::from(&args).validate_log_dir().is_err());
+
+        let dir = std::env::temp_dir();
+        let log_dir = format!("--log-dir={}", dir.display());
+        let args = Args::parse_from(["fuse", "--access-log", &log_dir, "connect", "mega"]);
+        assert_eq!(Config::from(&args).validate_log_dir(), Ok(Some(dir)));
+    }
+
+    #[test]
+    fn test_merge_mount_options() {
+        let file: ConfigFile =
+            toml::from_str(r#"options = ["noexec", "nosuid", "uid=1500"]"#).unwrap();
+        let args = Args::parse_from(["fuse", "connect", "mega", "-o", "exec,uid=1000"]);
+        let mut config = Config::from(&args);
+        config.merge(file);
+
+        assert_eq!(config.uid, Some(1000));
+        let options = config.validate_mount_options().unwrap();
+        assert!(options.contains(&MountOption::Exec));
+        assert!(options.contains(&MountOption::NoSuid));
+        assert!(!options.contains(&MountOption::NoExec));
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let args = Args::parse_from([
+            "fuse",
+            "--allow-other",
+            "connect",
+            "mega",
+            "-o",
+            "noexec,nosuid,suid,subtype=mega,noatime",
+        ]);
+        let options = Config::from(&args).validate_mount_options().unwrap();
+        assert_eq!(
+            options,
+            vec![
+                MountOption::RO,
+                MountOption::FSName("MegaFUSE".to_owned()),
+                MountOption::NoDev,
+                MountOption::AllowOther,
+                MountOption::NoExec,
+                MountOption::Suid,
+                MountOption::Subtype("mega".to_owned()),
+                MountOption::NoAtime,
+            ]
+        );
+
+        // Later values win, but not over the long options
+        let args = Args::parse_from([
+            "fuse",
+            "--gid=3",
+            "connect",
+            "mega",
+            "-o",
+            "uid=1,uid=2,gid=4",
+        ]);
+        let config = Config::from(&args);
+        assert_eq!((config.uid, config.gid), (Some(2), Some(3)));
+
+        for invalid in ["rw", "exec=1", "fsname=", "bogus"] {
+            let args = Args::parse_from(["fuse", "connect", "mega", "-o", invalid]);
+            assert!(Config::from(&args).validate_mount_options().is_err());
         }
     }
 }
@@ -0,0 +1,98 @@
+//! Access log recording which processes open and read which files of the
+//! mount, as JSON lines in `log_dir`. Builds can be replayed against it to
+//! find the files they actually touch, to tune sparse profiles and
+//! prefetching.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+const ACCESS_LOG: &str = "access.log";
+
+/// One line of the access log
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    pid: u32,
+    /// Name of the process at the time of the access, empty if it is gone
+    process: &'a str,
+    op: &'a str,
+    /// Path relative to the repository root
+    path: &'a Path,
+}
+
+/// Appends the accesses to `log_dir/access.log`, a line at a time so the log
+/// is complete while the mount is still up
+#[derive(Debug)]
+pub struct AccessLog {
+    writer: LineWriter<File>,
+}
+
+impl AccessLog {
+    /// Open the access log in `log_dir`, appending to previous mounts.
+    pub fn open(log_dir: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_dir.join(ACCESS_LOG))?;
+        Ok(AccessLog {
+            writer: LineWriter::new(file),
+        })
+    }
+
+    /// Record the operation `op` on `path` by process `pid`.
+    pub fn record(&mut self, pid: u32, op: &str, path: &Path) {
+        // Not cached: build tools fork and exec, so a pid changes name
+        let process = process_name(pid).unwrap_or_default();
+        let record = Record {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            pid,
+            process: &process,
+            op,
+            path,
+        };
+        let result = serde_json::to_writer(&mut self.writer, &record)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(err) = result {
+            warn!("Failed to write the access log: {}", err);
+        }
+    }
+}
+
+/// Name of process `pid`, as shown by `ps`.
+fn process_name(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = std::env::temp_dir().join(format!("mega-fuse-access-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut log = AccessLog::open(&dir).unwrap();
+        log.record(std::process::id(), "open", Path::new("src/main.rs"));
+
+        let content = fs::read_to_string(dir.join(ACCESS_LOG)).unwrap();
+        let record: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(record["pid"], std::process::id());
+        assert_eq!(record["op"], "open");
+        assert_eq!(record["path"], "src/main.rs");
+        assert!(!record["process"].as_str().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+//! Typed endpoints of the Mega API. Each request knows its endpoint, its query
+//! parameters and how to decode its response; paths and queries are built here
+//! only, percent-encoded, under the configured base path, version and
+//! repository root.
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::{
+    config::ApiSettings,
+    core::{
+        cache::is_object_id,
+        inode::{Object, Objects},
+    },
+};
+
+/// A request to an endpoint of the Mega API
+pub trait Endpoint {
+    /// What the body of a successful response decodes to
+    type Response;
+
+    /// Path of the endpoint under `<base path>/<version>`
+    fn endpoint(&self) -> &'static str;
+
+    /// Query parameters, not encoded yet.
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)>;
+
+    /// Decode the body of a response.
+    fn parse(body: Bytes) -> Result<Self::Response>;
+
+    /// Path and query of the request, encoded.
+    fn uri(&self, api: &ApiSettings) -> String {
+        let mut uri = format!("{}/{}/{}", api.base_path, api.version, self.endpoint());
+        let query = self.query(api);
+        if !query.is_empty() {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(query)
+                .finish();
+            uri.push('?');
+            uri.push_str(&query);
+        }
+        uri
+    }
+}
+
+/// `repo_path` of `path` in repository `repo`, the repository itself without
+/// a `path`.
+fn repo_path(api: &ApiSettings, repo: &str, path: Option<&Path>) -> String {
+    let mut repo_path = PathBuf::from(&api.repo_root);
+    repo_path.push(repo.trim_matches('/'));
+    if let Some(path) = path.filter(|path| !path.as_os_str().is_empty()) {
+        repo_path.push(path.strip_prefix("/").unwrap_or(path));
+    }
+    repo_path.to_string_lossy().into_owned()
+}
+
+/// Entries of a directory of a repository, by path or by object id
+#[derive(Clone, Debug)]
+pub struct TreeRequest {
+    /// Repository, relative to the repository root
+    pub repo: String,
+    /// Directory relative to the repository, its root by default
+    pub path: Option<PathBuf>,
+    /// Object id of the directory, preferred over `path`
+    pub object_id: Option<String>,
+}
+
+impl TreeRequest {
+    /// The root directory of `repo`.
+    pub fn root(repo: &str) -> Self {
+        TreeRequest {
+            repo: repo.to_owned(),
+            path: None,
+            object_id: None,
+        }
+    }
+
+    /// The directory at `path` in `repo`.
+    pub fn at_path(repo: &str, path: &Path) -> Self {
+        TreeRequest {
+            path: Some(path.to_path_buf()),
+            ..Self::root(repo)
+        }
+    }
+
+    /// The directory with object id `id` in `repo`.
+    pub fn with_id(repo: &str, id: &str) -> Self {
+        TreeRequest {
+            object_id: Some(id.to_owned()),
+            ..Self::root(repo)
+        }
+    }
+}
+
+impl Endpoint for TreeRequest {
+    type Response = Objects;
+
+    fn endpoint(&self) -> &'static str {
+        "tree"
+    }
+
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        if let Some(id) = &self.object_id {
+            query.push(("object_id", id.clone()));
+        }
+        query.push((
+            "repo_path",
+            repo_path(api, &self.repo, self.path.as_deref()),
+        ));
+        query
+    }
+
+    fn parse(body: Bytes) -> Result<Objects> {
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Entries of several directories of a repository and of their subdirectories,
+/// in one request
+#[derive(Clone, Debug)]
+pub struct SubtreesRequest {
+    /// Repository, relative to the repository root
+    pub repo: String,
+    /// Object ids of the directories
+    pub object_ids: Vec<String>,
+    /// Levels of directories listed, 1 for the directories themselves only
+    pub depth: u32,
+}
+
+/// A directory listed by `SubtreesRequest`
+#[derive(Debug, Deserialize)]
+pub struct Subtree {
+    /// Object id of the directory
+    pub object_id: String,
+    /// Its entries
+    #[serde(rename(deserialize = "items"))]
+    pub data: Vec<Object>,
+}
+
+/// Directories listed by `SubtreesRequest`, in no particular order
+#[derive(Debug, Deserialize)]
+pub struct Subtrees {
+    /// The directories
+    #[serde(rename(deserialize = "items"))]
+    pub data: Vec<Subtree>,
+}
+
+impl Endpoint for SubtreesRequest {
+    type Response = Subtrees;
+
+    fn endpoint(&self) -> &'static str {
+        "tree/batch"
+    }
+
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)> {
+        let mut query: Vec<_> = self
+            .object_ids
+            .iter()
+            .map(|id| ("object_id", id.clone()))
+            .collect();
+        query.push(("depth", self.depth.to_string()));
+        query.push(("repo_path", repo_path(api, &self.repo, None)));
+        query
+    }
+
+    fn parse(body: Bytes) -> Result<Subtrees> {
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Raw content of an object of a repository, of any type
+#[derive(Clone, Debug)]
+pub struct ObjectRequest {
+    /// Repository, relative to the repository root
+    pub repo: String,
+    /// Object id
+    pub object_id: String,
+}
+
+impl ObjectRequest {
+    /// The object with id `id` in `repo`.
+    pub fn new(repo: &str, id: &str) -> Self {
+        ObjectRequest {
+            repo: repo.to_owned(),
+            object_id: id.to_owned(),
+        }
+    }
+}
+
+impl Endpoint for ObjectRequest {
+    type Response = Bytes;
+
+    fn endpoint(&self) -> &'static str {
+        "object"
+    }
+
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)> {
+        vec![
+            ("object_id", self.object_id.clone()),
+            ("repo_path", repo_path(api, &self.repo, None)),
+        ]
+    }
+
+    fn parse(body: Bytes) -> Result<Bytes> {
+        Ok(body)
+    }
+}
+
+/// Content of a file, by the object id of its blob
+#[derive(Clone, Debug)]
+pub struct BlobRequest {
+    /// Object id
+    pub object_id: String,
+}
+
+impl Endpoint for BlobRequest {
+    type Response = Bytes;
+
+    fn endpoint(&self) -> &'static str {
+        "blob"
+    }
+
+    fn query(&self, _api: &ApiSettings) -> Vec<(&'static str, String)> {
+        vec![("object_id", self.object_id.clone())]
+    }
+
+    fn parse(body: Bytes) -> Result<Bytes> {
+        Ok(body)
+    }
+}
+
+/// Latest commit touching a path of a repository
+#[derive(Clone, Debug)]
+pub struct CommitRequest {
+    /// Repository, relative to the repository root
+    pub repo: String,
+    /// Path relative to the repository, the whole repository by default
+    pub path: Option<PathBuf>,
+}
+
+/// Commit returned by `CommitRequest`
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommitInfo {
+    /// Commit id
+    pub oid: String,
+    /// First line of the commit message
+    #[serde(default)]
+    pub short_message: String,
+    /// Commit date
+    #[serde(default)]
+    pub date: String,
+}
+
+impl Endpoint for CommitRequest {
+    type Response = CommitInfo;
+
+    fn endpoint(&self) -> &'static str {
+        "latest-commit"
+    }
+
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)> {
+        vec![("path", repo_path(api, &self.repo, self.path.as_deref()))]
+    }
+
+    fn parse(body: Bytes) -> Result<CommitInfo> {
+        let commit: CommitInfo = serde_json::from_slice(&body)?;
+        // Names the snapshot files and goes into the `.git` view
+        if !is_object_id(&commit.oid) {
+            bail!("{:?} is not a commit id", commit.oid);
+        }
+        Ok(commit)
+    }
+}
+
+/// Branches and tags of a repository
+#[derive(Clone, Debug)]
+pub struct RefsRequest {
+    /// Repository, relative to the repository root
+    pub repo: String,
+}
+
+/// A branch or tag returned by `RefsRequest`
+#[derive(Clone, Debug, Deserialize)]
+pub struct RefInfo {
+    /// Full name, e.g. `refs/heads/main`
+    pub name: String,
+    /// Commit id the ref points to
+    pub oid: String,
+}
+
+impl Endpoint for RefsRequest {
+    type Response = Vec<RefInfo>;
+
+    fn endpoint(&self) -> &'static str {
+        "refs"
+    }
+
+    fn query(&self, api: &ApiSettings) -> Vec<(&'static str, String)> {
+        vec![("repo_path", repo_path(api, &self.repo, None))]
+    }
+
+    fn parse(body: Bytes) -> Result<Vec<RefInfo>> {
+        let refs: Vec<RefInfo> = serde_json::from_slice(&body)?;
+        if let Some(invalid) = refs.iter().find(|r| !is_object_id(&r.oid)) {
+            bail!("{:?} is not a commit id", invalid.oid);
+        }
+        Ok(refs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri() {
+        let api = ApiSettings::default();
+        assert_eq!(
+            TreeRequest::root("fuser").uri(&api),
+            "/api/v1/tree?repo_path=%2Fprojects%2Ffuser"
+        );
+        assert_eq!(
+            TreeRequest::with_id("third-party/mega", "d2c7").uri(&api),
+            "/api/v1/tree?object_id=d2c7&repo_path=%2Fprojects%2Fthird-party%2Fmega"
+        );
+        // Spaces, separators and unicode never leak into the query string
+        assert_eq!(
+            TreeRequest::at_path("R&D", Path::new("docs/é t")).uri(&api),
+            "/api/v1/tree?repo_path=%2Fprojects%2FR%26D%2Fdocs%2F%C3%A9+t"
+        );
+
+        let api = ApiSettings {
+            base_path: String::new(),
+            version: "v2".to_owned(),
+            repo_root: "/".to_owned(),
+        };
+        assert_eq!(
+            ObjectRequest::new("fuser", "d2c7").uri(&api),
+            "/v2/object?object_id=d2c7&repo_path=%2Ffuser"
+        );
+        assert_eq!(
+            BlobRequest {
+                object_id: "d2c7".to_owned()
+            }
+            .uri(&api),
+            "/v2/blob?object_id=d2c7"
+        );
+        assert_eq!(
+            CommitRequest {
+                repo: "fuser".to_owned(),
+                path: None,
+            }
+            .uri(&api),
+            "/v2/latest-commit?path=%2Ffuser"
+        );
+        let subtrees = SubtreesRequest {
+            repo: "fuser".to_owned(),
+            object_ids: vec!["d2c7".to_owned(), "8452".to_owned()],
+            depth: 3,
+        };
+        assert_eq!(
+            subtrees.uri(&api),
+            "/v2/tree/batch?object_id=d2c7&object_id=8452&depth=3&repo_path=%2Ffuser"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let commit = CommitRequest::parse(Bytes::from_static(
+            br#"{"oid":"b6eb9ec1a1d1e0f2c3b4a5968778695a4b3c2d1e","short_message":"Fix","author":"mega"}"#,
+        ))
+        .unwrap();
+        assert_eq!(commit.oid, "b6eb9ec1a1d1e0f2c3b4a5968778695a4b3c2d1e");
+        assert!(commit.date.is_empty());
+        // Commit ids end up in paths
+        assert!(CommitRequest::parse(Bytes::from_static(br#"{"oid":"../b6eb9ec1"}"#)).is_err());
+
+        let refs = RefsRequest::parse(Bytes::from_static(
+            br#"[{"name":"refs/heads/main","oid":"b6eb9ec1a1d1e0f2c3b4a5968778695a4b3c2d1e"}]"#,
+        ))
+        .unwrap();
+        assert_eq!(refs[0].name, "refs/heads/main");
+        assert!(RefsRequest::parse(Bytes::from_static(
+            br#"[{"name":"refs/heads/main","oid":"b6eb\nrefs"}]"#,
+        ))
+        .is_err());
+        assert!(TreeRequest::parse(Bytes::from_static(b"not json")).is_err());
+
+        let subtrees = SubtreesRequest::parse(Bytes::from_static(
+            br#"{"items":[{"object_id":"d2c7","items":[]}]}"#,
+        ))
+        .unwrap();
+        assert_eq!(subtrees.data[0].object_id, "d2c7");
+        assert!(subtrees.data[0].data.is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+//! On-disk blob cache. Blobs are immutable per object id, so the id alone is
+//! enough to address the content and a cached blob never goes stale.
+//!
+//! A cache directory may be shared by every mount of the machine: blobs are
+//! renamed into place once complete, downloads of a blob are serialized with a
+//! lock file so each blob is downloaded once, and counters are updated under a
+//! lock as well. The counters include the number and size of the cached blobs,
+//! so reporting them does not walk the cache.
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{debug_span, field};
+
+use crate::core::{
+    git_view::{GitView, GIT_VIEWS_DIR},
+    metrics,
+    snapshot::{TreeSnapshot, TREES_DIR},
+};
+
+const BLOBS_DIR: &str = "blobs";
+/// Lock files of the blobs being downloaded
+const LOCKS_DIR: &str = "locks";
+/// Counters accumulated by every mount using the cache
+const STATS_FILE: &str = "stats.json";
+/// Lock file serializing the updates of `STATS_FILE`
+const STATS_LOCK: &str = "stats.lock";
+
+/// Object id of `content` as a git blob: the SHA-1 of `blob <len>\0<content>`.
+pub fn blob_id(content: &[u8]) -> String {
+    object_id("blob", content)
+}
+
+/// Whether `id` is a git object id: 40 lowercase hex digits. Ids come from
+/// the server and name files of the cache, anything else is rejected.
+pub fn is_object_id(id: &str) -> bool {
+    id.len() == 40 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Object id of `content` as a git object of type `kind`.
+pub fn object_id(kind: &str, content: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{} {}\0", kind, content.len()));
+    hasher.update(content);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Counters persisted in `cache_dir`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    /// Number of cached blobs, `None` until counted
+    #[serde(default)]
+    blobs: Option<u64>,
+    /// Total size of cached blobs in bytes, `None` until counted
+    #[serde(default)]
+    bytes: Option<u64>,
+}
+
+/// Summary of the content and usage of a `BlobCache`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Number of cached blobs
+    pub blobs: u64,
+    /// Total size of cached blobs in bytes
+    pub bytes: u64,
+    /// Lookups served from the cache
+    pub hits: u64,
+    /// Lookups which had to download the blob
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of lookups served from the cache, if there were any.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            total => Some(self.hits as f64 / total as f64),
+        }
+    }
+}
+
+impl Counters {
+    fn add_blobs(&mut self, blobs: u64, bytes: u64) {
+        self.blobs = self.blobs.map(|total| total + blobs);
+        self.bytes = self.bytes.map(|total| total + bytes);
+    }
+
+    fn remove_blobs(&mut self, blobs: u64, bytes: u64) {
+        self.blobs = self.blobs.map(|total| total.saturating_sub(blobs));
+        self.bytes = self.bytes.map(|total| total.saturating_sub(bytes));
+    }
+}
+
+/// Exclusive lock on the download of a blob, shared with other processes
+/// using the same cache. Released when dropped.
+#[derive(Debug)]
+pub struct BlobLock {
+    _file: File,
+    path: PathBuf,
+    blob: PathBuf,
+}
+
+impl Drop for BlobLock {
+    fn drop(&mut self) {
+        // Once the blob is in place, whoever opened the lock file in the
+        // meantime finds it cached after acquiring the lock, so the lock file
+        // can go. It stays after a failed download, which is harmless.
+        if self.blob.is_file() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A cached blob found while scanning the cache
+#[derive(Debug)]
+struct Entry {
+    id: String,
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Content addressed storage of blobs under `cache_dir`, laid out like git's
+/// loose objects: `blobs/<first two hex digits>/<remaining hex digits>`.
+#[derive(Debug)]
+pub struct BlobCache {
+    cache_dir: PathBuf,
+    root: PathBuf,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlobCache {
+    /// Create a cache rooted at `cache_dir`. Directories are created on demand.
+    pub fn new(cache_dir: &Path) -> Self {
+        BlobCache {
+            cache_dir: cache_dir.to_path_buf(),
+            root: cache_dir.join(BLOBS_DIR),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Location of the blob with object id `id`, an error if `id` is not an
+    /// object id.
+    pub fn path(&self, id: &str) -> io::Result<PathBuf> {
+        check_object_id(id)?;
+        Ok(self.root.join(&id[..2]).join(&id[2..]))
+    }
+
+    /// Open the cached blob with object id `id`, if present. Counts as a hit
+    /// or a miss of the cache.
+    pub fn get(&self, id: &str) -> Option<File> {
+        let span = debug_span!("cache_get", id, hit = field::Empty).entered();
+        let file = self.open(id);
+        span.record("hit", file.is_some());
+        match file {
+            Some(file) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::global().record_cache_lookup(true);
+                Some(file)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                metrics::global().record_cache_lookup(false);
+                None
+            }
+        }
+    }
+
+    /// Open the cached blob with object id `id`, if present, without counting
+    /// it as a lookup.
+    pub fn open(&self, id: &str) -> Option<File> {
+        File::open(self.path(id).ok()?).ok()
+    }
+
+    /// Whether the blob with object id `id` is cached.
+    pub fn contains(&self, id: &str) -> bool {
+        self.path(id).is_ok_and(|path| path.is_file())
+    }
+
+    /// Store `content` as the blob with object id `id` and return it opened
+    /// for reading. The blob is written aside and renamed into place, so a
+    /// concurrent reader never sees a partially written blob.
+    pub fn insert(&self, id: &str, content: &[u8]) -> io::Result<File> {
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let _span = debug_span!("cache_insert", id, bytes = content.len()).entered();
+
+        let path = self.path(id)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension(format!(
+            "tmp.{}.{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::create(&tmp)?;
+        file.write_all(content)?;
+        let added = !path.is_file();
+        fs::rename(&tmp, &path)?;
+        if added {
+            self.update_counters(|counters| counters.add_blobs(1, content.len() as u64))?;
+        }
+        File::open(path)
+    }
+
+    /// Lock the download of the blob with object id `id`, waiting for the
+    /// process holding the lock, if any. Check the cache again once locked: the
+    /// blob may have been downloaded while waiting.
+    pub fn lock(&self, id: &str) -> io::Result<BlobLock> {
+        let _span = debug_span!("cache_lock", id).entered();
+        let path = self.lock_path(id)?;
+        let file = lock_file(&path, false)?.expect("blocking lock");
+        Ok(BlobLock {
+            _file: file,
+            path,
+            blob: self.path(id)?,
+        })
+    }
+
+    /// Lock the download of the blob with object id `id` unless another
+    /// process or thread is already downloading it.
+    pub fn try_lock(&self, id: &str) -> io::Result<Option<BlobLock>> {
+        let path = self.lock_path(id)?;
+        let blob = self.path(id)?;
+        Ok(lock_file(&path, true)?.map(|file| BlobLock {
+            _file: file,
+            path,
+            blob,
+        }))
+    }
+
+    /// Remove the blob with object id `id`.
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        let path = self.path(id)?;
+        let Ok(meta) = fs::metadata(&path) else {
+            return Ok(());
+        };
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            Err(_) => Ok(()),
+            Ok(()) => self.update_counters(|counters| counters.remove_blobs(1, meta.len())),
+        }
+    }
+
+    /// Total size in bytes of all cached blobs.
+    pub fn usage(&self) -> u64 {
+        self.stats().bytes
+    }
+
+    /// Content of the cache along with the hit and miss counters of all
+    /// mounts, including the ones of this instance not flushed yet.
+    pub fn stats(&self) -> CacheStats {
+        let counters = self.load_counters();
+        let (blobs, bytes) = match (counters.blobs, counters.bytes) {
+            (Some(blobs), Some(bytes)) => (blobs, bytes),
+            _ => self.count_blobs(),
+        };
+        CacheStats {
+            blobs,
+            bytes,
+            hits: counters.hits + self.hits.load(Ordering::Relaxed),
+            misses: counters.misses + self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Add the hit and miss counters of this instance to the persisted ones.
+    pub fn flush_stats(&self) -> io::Result<()> {
+        self.update_counters(|counters| {
+            counters.hits += self.hits.swap(0, Ordering::Relaxed);
+            counters.misses += self.misses.swap(0, Ordering::Relaxed);
+        })
+    }
+
+    /// Remove blobs not used for `older_than`, then the least recently used
+    /// ones until the cache fits in `max_size` bytes. Returns the number of
+    /// blobs and bytes removed.
+    pub fn prune(
+        &self,
+        max_size: Option<u64>,
+        older_than: Option<Duration>,
+    ) -> io::Result<(u64, u64)> {
+        let mut entries = self.entries();
+        // Least recently used first
+        entries.sort_by_key(|entry| entry.last_used);
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let now = SystemTime::now();
+        let (mut removed, mut freed) = (0, 0);
+        for entry in entries {
+            let expired = older_than.is_some_and(|age| {
+                now.duration_since(entry.last_used)
+                    .is_ok_and(|unused| unused > age)
+            });
+            let oversized = max_size.is_some_and(|max_size| size > max_size);
+            if !expired && !oversized {
+                continue;
+            }
+            fs::remove_file(&entry.path)?;
+            size -= entry.size;
+            removed += 1;
+            freed += entry.size;
+        }
+        self.update_counters(|counters| counters.remove_blobs(removed, freed))?;
+        metrics::global().record_evictions(removed);
+        Ok((removed, freed))
+    }
+
+    /// Hash every cached blob and remove the ones whose content does not
+    /// match their object id. Returns the ids of the corrupted blobs.
+    pub fn verify(&self) -> io::Result<Vec<String>> {
+        let (mut corrupted, mut freed) = (Vec::new(), 0);
+        for entry in self.entries() {
+            let content = fs::read(&entry.path)?;
+            if blob_id(&content) != entry.id {
+                fs::remove_file(&entry.path)?;
+                corrupted.push(entry.id);
+                freed += entry.size;
+            }
+        }
+        self.update_counters(|counters| counters.remove_blobs(corrupted.len() as u64, freed))?;
+        Ok(corrupted)
+    }
+
+    /// Remove every cached blob, the tree snapshots, the loose objects of the
+    /// `.git` views and the persisted counters.
+    pub fn clear(&self) -> io::Result<()> {
+        let results = [
+            fs::remove_dir_all(&self.root),
+            fs::remove_dir_all(self.cache_dir.join(LOCKS_DIR)),
+            fs::remove_dir_all(self.cache_dir.join(TREES_DIR)),
+            fs::remove_dir_all(self.cache_dir.join(GIT_VIEWS_DIR)),
+            fs::remove_file(self.cache_dir.join(STATS_FILE)),
+        ];
+        for result in results {
+            match result {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the tree snapshots and the `.git` view of `repo`, and the blobs
+    /// only referenced by the snapshots. Returns the number of blobs removed.
+    pub fn clear_repo(&self, repo: &str) -> io::Result<u64> {
+        let snapshots = TreeSnapshot::load_all(&self.cache_dir);
+        let (own, others): (Vec<_>, Vec<_>) = snapshots
+            .iter()
+            .partition(|snapshot| snapshot.repo() == repo);
+        let shared: HashSet<&str> = others.iter().flat_map(|s| s.blob_ids()).collect();
+        let exclusive: HashSet<&str> = own
+            .iter()
+            .flat_map(|s| s.blob_ids())
+            .filter(|id| !shared.contains(id))
+            .collect();
+
+        let mut removed = 0;
+        for id in exclusive {
+            if self.contains(id) {
+                self.remove(id)?;
+                removed += 1;
+            }
+        }
+        TreeSnapshot::remove_repo(&self.cache_dir, repo)?;
+        match fs::remove_dir_all(GitView::repo_dir(&self.cache_dir, repo)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        Ok(removed)
+    }
+
+    fn lock_path(&self, id: &str) -> io::Result<PathBuf> {
+        check_object_id(id)?;
+        Ok(self.cache_dir.join(LOCKS_DIR).join(id))
+    }
+
+    fn load_counters(&self) -> Counters {
+        fs::read(self.cache_dir.join(STATS_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Apply `update` to the persisted counters under the stats lock. If the
+    /// counters predate the totals, the blobs are counted instead, the scan
+    /// already reflecting the update.
+    fn update_counters(&self, update: impl FnOnce(&mut Counters)) -> io::Result<()> {
+        fs::create_dir_all(&self.cache_dir)?;
+        let _lock = lock_file(&self.cache_dir.join(STATS_LOCK), false)?;
+        let mut counters = self.load_counters();
+        update(&mut counters);
+        if counters.blobs.is_none() || counters.bytes.is_none() {
+            let (blobs, bytes) = self.count_blobs();
+            (counters.blobs, counters.bytes) = (Some(blobs), Some(bytes));
+        }
+        let path = self.cache_dir.join(STATS_FILE);
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(&counters)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Number and total size of the cached blobs, scanning the cache.
+    fn count_blobs(&self) -> (u64, u64) {
+        let entries = self.entries();
+        let bytes = entries.iter().map(|entry| entry.size).sum();
+        (entries.len() as u64, bytes)
+    }
+
+    /// Scan the cache for blobs, skipping blobs still being written.
+    fn entries(&self) -> Vec<Entry> {
+        let Ok(prefixes) = fs::read_dir(&self.root) else {
+            return Vec::new();
+        };
+        let mut entries = Vec::new();
+        for prefix in prefixes.filter_map(|prefix| prefix.ok()) {
+            let Ok(blobs) = fs::read_dir(prefix.path()) else {
+                continue;
+            };
+            let prefix = prefix.file_name().to_string_lossy().into_owned();
+            for blob in blobs.filter_map(|blob| blob.ok()) {
+                let name = blob.file_name().to_string_lossy().into_owned();
+                let Ok(meta) = blob.metadata() else {
+                    continue;
+                };
+                let id = format!("{}{}", prefix, name);
+                // Blobs being written, or files not put there by the cache
+                if !is_object_id(&id) || !meta.is_file() {
+                    continue;
+                }
+                let last_used = meta
+                    .accessed()
+                    .or_else(|_| meta.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push(Entry {
+                    id,
+                    path: blob.path(),
+                    size: meta.len(),
+                    last_used,
+                });
+            }
+        }
+        entries
+    }
+}
+
+/// `is_object_id`, as an error to return.
+pub(crate) fn check_object_id(id: &str) -> io::Result<()> {
+    match is_object_id(id) {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not an object id", id),
+        )),
+    }
+}
+
+/// Open or create the file at `path` and take an exclusive `flock` on it.
+/// Returns `None` if `nonblocking` and the lock is held elsewhere.
+fn lock_file(path: &Path, nonblocking: bool) -> io::Result<Option<File>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    let operation = if nonblocking {
+        libc::LOCK_EX | libc::LOCK_NB
+    } else {
+        libc::LOCK_EX
+    };
+    loop {
+        // SAFETY: the descriptor is owned by `file` and stays open
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(Some(file));
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock => return Ok(None),
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn temp_cache_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mega-fuse-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_blob_id() {
+        // `echo hello | git hash-object --stdin`
+        assert_eq!(
+            blob_id(b"hello\n"),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let dir = temp_cache_dir("cache");
+        let cache = BlobCache::new(&dir);
+        let id = "d2c73088bc71e8b6ce07ec2e95087b57c42286d4";
+        assert!(cache.get(id).is_none());
+
+        cache.insert(id, b"hello").unwrap();
+        let mut content = String::new();
+        cache.get(id).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+        assert!(cache
+            .path(id)
+            .unwrap()
+            .ends_with("d2/c73088bc71e8b6ce07ec2e95087b57c42286d4"));
+        assert_eq!(cache.usage(), 5);
+
+        // Ids of the server never escape the cache nor panic
+        for invalid in ["../../../etc/passwd", "d2", "é", &id.to_uppercase()] {
+            assert!(cache.insert(invalid, b"hello").is_err());
+            assert!(cache.lock(invalid).is_err());
+            assert!(cache.try_lock(invalid).is_err());
+            assert!(cache.open(invalid).is_none());
+        }
+
+        let stats = cache.stats();
+        assert_eq!((stats.blobs, stats.hits, stats.misses), (1, 1, 1));
+        cache.flush_stats().unwrap();
+        assert_eq!(BlobCache::new(&dir).stats().hit_ratio(), Some(0.5));
+        // The totals are kept along with the counters
+        let counters = cache.load_counters();
+        assert_eq!((counters.blobs, counters.bytes), (Some(1), Some(5)));
+        cache.remove(id).unwrap();
+        assert_eq!((cache.stats().blobs, cache.usage()), (0, 0));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let dir = temp_cache_dir("cache-shared");
+        // Two mounts sharing the cache
+        let (first, second) = (BlobCache::new(&dir), BlobCache::new(&dir));
+        let id = blob_id(b"shared\n");
+
+        let lock = first.lock(&id).unwrap();
+        assert!(second.try_lock(&id).unwrap().is_none());
+        first.insert(&id, b"shared\n").unwrap();
+        drop(lock);
+        assert!(!first.lock_path(&id).unwrap().exists());
+        assert!(second.try_lock(&id).unwrap().is_some());
+        assert!(second.get(&id).is_some());
+
+        first.get("0123456789abcdef0123456789abcdef01234567");
+        first.flush_stats().unwrap();
+        second.flush_stats().unwrap();
+        let stats = BlobCache::new(&dir).stats();
+        assert_eq!((stats.blobs, stats.hits, stats.misses), (1, 1, 1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_verify_and_clear() {
+        let dir = temp_cache_dir("cache-maintenance");
+        let cache = BlobCache::new(&dir);
+        let hello = blob_id(b"hello\n");
+        cache.insert(&hello, b"hello\n").unwrap();
+        cache
+            .insert("0123456789abcdef0123456789abcdef01234567", b"corrupted")
+            .unwrap();
+
+        let corrupted = cache.verify().unwrap();
+        assert_eq!(corrupted, vec!["0123456789abcdef0123456789abcdef01234567"]);
+        assert_eq!(cache.stats().blobs, 1);
+
+        assert_eq!(cache.prune(Some(1024), None).unwrap(), (0, 0));
+        assert_eq!(cache.prune(Some(0), None).unwrap(), (1, 6));
+
+        cache.insert(&hello, b"hello\n").unwrap();
+        fs::create_dir_all(dir.join(TREES_DIR).join("mega")).unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.stats().blobs, 0);
+        assert!(!dir.join(TREES_DIR).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+//! Read-only `.git` directory at the root of the mount, so `git log`, `git
+//! show` and `git blame` work on the mounted tree without a clone.
+//!
+//! There is no index: `git status` and `git diff` without revisions see every
+//! file as untracked, only commands reading objects and refs are supported
+//! (`git diff` between revisions included).
+//!
+//! The view presents the mounted commit as a detached `HEAD`, the branches and
+//! tags of the Mega server as `packed-refs`, and serves every
+//! object git asks for as a loose object, looked up by name under `objects`:
+//! trees of the mounted tree are rebuilt from the inode table when they hash to
+//! their id, blobs come from the blob cache, anything else is requested from
+//! the Mega object API on first lookup. Loose objects are kept in `cache_dir`
+//! per repository, so the history read once stays available offline.
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use fuser::FUSE_ROOT_ID;
+
+use crate::core::{
+    api::RefInfo,
+    cache::object_id,
+    handle::FileHandle,
+    inode::{
+        ContentType, Inode, InodeAttributes, DEFAULT_DIR_PERMISSIONS, DEFAULT_FILE_PERMISSIONS,
+    },
+    snapshot::write_atomically,
+};
+
+/// Name of the view at the root of the mount
+pub const GIT_DIR: &str = ".git";
+/// Directory of `cache_dir` holding the views of every repository
+pub const GIT_VIEWS_DIR: &str = "git";
+const OBJECTS_DIR: &str = "objects";
+const PACKED_REFS: &str = "packed-refs";
+const CONFIG: &str = "[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = false\n";
+
+/// Types of git objects, in the order a fetched object is hashed as
+const OBJECT_KINDS: [&str; 4] = ["commit", "tree", "blob", "tag"];
+
+/// The `.git` directory of a mount. Its inodes live apart from the mounted
+/// tree, so they are never part of a tree snapshot.
+#[derive(Debug)]
+pub struct GitView {
+    /// Directory of the repository in `cache_dir`, holding the files served
+    dir: PathBuf,
+    inodes: HashMap<u64, Inode>,
+    root: u64,
+    /// Inode of `objects`, where the fan-out directories are created
+    objects: u64,
+    /// Directories of the mounted tree by object id, to rebuild their trees
+    trees: HashMap<String, u64>,
+}
+
+impl GitView {
+    /// Build the view of `repo` at commit `head` with the branches and tags
+    /// `refs` over the mounted `inodes`, keeping its files in `cache_dir`.
+    /// Without `refs`, as when offline, the last known ones are served.
+    pub fn new(
+        cache_dir: &Path,
+        repo: &str,
+        head: &str,
+        refs: &[RefInfo],
+        inodes: &HashMap<u64, Inode>,
+    ) -> io::Result<GitView> {
+        let dir = Self::repo_dir(cache_dir, repo);
+        fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+        // Mounts of the repository at other commits share the directory
+        let head_file = format!("HEAD.{}", head);
+        write_atomically(&dir.join(&head_file), format!("{}\n", head).as_bytes())?;
+        write_atomically(&dir.join("config"), CONFIG.as_bytes())?;
+        if !refs.is_empty() {
+            write_atomically(&dir.join(PACKED_REFS), packed_refs(refs).as_bytes())?;
+        }
+        let packed_refs_size = fs::metadata(dir.join(PACKED_REFS)).map(|m| m.len()).ok();
+
+        let root = Inode::new(FUSE_ROOT_ID, dir_attr(GIT_DIR));
+        let mut view = GitView {
+            dir,
+            root: root.ino,
+            objects: 0,
+            inodes: HashMap::from([(root.ino, root)]),
+            trees: inodes
+                .values()
+                .filter(|inode| inode.attr.kind == ContentType::Dir)
+                .map(|inode| (inode.attr.id.clone(), inode.ino))
+                .collect(),
+        };
+        let root = view.root;
+        view.insert(root, file_attr("HEAD", &head_file, head.len() as u64 + 1));
+        view.insert(root, file_attr("config", "config", CONFIG.len() as u64));
+        if let Some(size) = packed_refs_size {
+            view.insert(root, file_attr(PACKED_REFS, PACKED_REFS, size));
+        }
+        let refs = view.insert(root, dir_attr("refs"));
+        view.insert(refs, dir_attr("heads"));
+        view.insert(refs, dir_attr("tags"));
+        view.objects = view.insert(root, dir_attr(OBJECTS_DIR));
+        view.insert(view.objects, dir_attr("info"));
+        view.insert(view.objects, dir_attr("pack"));
+        Ok(view)
+    }
+
+    /// Directory holding the view of `repo`.
+    pub fn repo_dir(cache_dir: &Path, repo: &str) -> PathBuf {
+        let name = repo.trim_matches('/').replace('/', "%2F");
+        cache_dir.join(GIT_VIEWS_DIR).join(name)
+    }
+
+    /// Inode of the `.git` directory.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// The inode `ino`, if it belongs to the view.
+    pub fn get(&self, ino: u64) -> Option<&Inode> {
+        self.inodes.get(&ino)
+    }
+
+    /// Directory of the mounted tree with object id `id`.
+    pub fn tree(&self, id: &str) -> Option<u64> {
+        self.trees.get(id).copied()
+    }
+
+    /// Find `name` in the directory `parent` of the view. Fan-out directories
+    /// of `objects` are created on demand, and so are loose objects already
+    /// in `cache_dir`.
+    pub fn lookup_name(&mut self, parent: u64, name: &str) -> Option<u64> {
+        let inode = self.inodes.get(&parent)?;
+        let found = inode
+            .children_ino
+            .iter()
+            .find(|ino| self.inodes[ino].attr.name == name);
+        if let Some(ino) = found {
+            return Some(*ino);
+        }
+        if parent == self.objects && is_hex(name, 2) {
+            return Some(self.insert(parent, dir_attr(name)));
+        }
+        let id = self.object_id(parent, name)?;
+        let path = object_path(&id);
+        let size = fs::metadata(self.dir.join(&path)).ok()?.len();
+        Some(self.insert(parent, file_attr(name, &path, size)))
+    }
+
+    /// Id of the object named `name` in the fan-out directory `parent`.
+    pub fn object_id(&self, parent: u64, name: &str) -> Option<String> {
+        let fan_out = self.inodes.get(&parent)?;
+        if fan_out.parent_ino != self.objects || !is_hex(name, 38) {
+            return None;
+        }
+        Some(format!("{}{}", fan_out.attr.name, name))
+    }
+
+    /// Store the object `id` of type `kind` as a loose object and expose it in
+    /// the fan-out directory `parent`.
+    pub fn insert_object(
+        &mut self,
+        parent: u64,
+        id: &str,
+        kind: &str,
+        content: &[u8],
+    ) -> io::Result<u64> {
+        let path = object_path(id);
+        let loose = loose_object(kind, content)?;
+        fs::create_dir_all(self.dir.join(OBJECTS_DIR).join(&id[..2]))?;
+        write_atomically(&self.dir.join(&path), &loose)?;
+        Ok(self.insert(parent, file_attr(&id[2..], &path, loose.len() as u64)))
+    }
+
+    /// Open the file `ino`, `None` if it does not belong to the view.
+    pub fn open(&self, ino: u64) -> Option<io::Result<FileHandle>> {
+        let inode = self.inodes.get(&ino)?;
+        if inode.attr.kind == ContentType::Dir {
+            return Some(Err(io::Error::from_raw_os_error(libc::EISDIR)));
+        }
+        let handle = File::open(self.dir.join(&inode.attr.path))
+            .and_then(|file| FileHandle::new(ino, inode.attr.id.clone(), file))
+            .map(|mut handle| {
+                // Only accesses to the mounted tree are of interest
+                handle.logged_read = true;
+                handle
+            });
+        Some(handle)
+    }
+
+    fn insert(&mut self, parent: u64, attr: InodeAttributes) -> u64 {
+        let inode = Inode::new(parent, attr);
+        let ino = inode.ino;
+        self.inodes.insert(ino, inode);
+        if let Some(parent) = self.inodes.get_mut(&parent) {
+            parent.insert_child(ino);
+        }
+        ino
+    }
+}
+
+/// Content of the tree object listing `entries`, `None` if an id is not a
+/// valid object id.
+pub fn tree_content<'a>(entries: impl IntoIterator<Item = &'a InodeAttributes>) -> Option<Vec<u8>> {
+    let mut entries: Vec<&InodeAttributes> = entries.into_iter().collect();
+    // Git sorts directories as if their name ended with a slash
+    entries.sort_by_cached_key(|attr| {
+        let mut key = attr.name.clone().into_bytes();
+        if attr.kind == ContentType::Dir {
+            key.push(b'/');
+        }
+        key
+    });
+    let mut content = Vec::new();
+    for attr in entries {
+        let mode = match attr.kind {
+            ContentType::Dir => "40000",
+            ContentType::File if attr.permissions & 0o111 != 0 => "100755",
+            ContentType::File => "100644",
+        };
+        content.extend_from_slice(format!("{} {}\0", mode, attr.name).as_bytes());
+        content.extend_from_slice(&decode_hex(&attr.id)?);
+    }
+    Some(content)
+}
+
+/// Type of the object `id` fetched as `content`, `None` if it hashes to
+/// another id whatever its type.
+pub fn object_kind(id: &str, content: &[u8]) -> Option<&'static str> {
+    OBJECT_KINDS
+        .into_iter()
+        .find(|kind| object_id(kind, content).eq_ignore_ascii_case(id))
+}
+
+/// `content` as stored by git in a loose object file.
+fn loose_object(kind: &str, content: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    write!(encoder, "{} {}\0", kind, content.len())?;
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+/// Location of the loose object `id` relative to the view directory.
+fn object_path(id: &str) -> String {
+    format!("{}/{}/{}", OBJECTS_DIR, &id[..2], &id[2..])
+}
+
+fn is_hex(name: &str, len: usize) -> bool {
+    name.len() == len && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn decode_hex(id: &str) -> Option<Vec<u8>> {
+    if !is_hex(id, 40) {
+        return None;
+    }
+    (0..id.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&id[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn dir_attr(name: &str) -> InodeAttributes {
+    view_attr(name, "", 0, ContentType::Dir, DEFAULT_DIR_PERMISSIONS)
+}
+
+/// `packed-refs` listing `refs`, sorted by name as git expects.
+fn packed_refs(refs: &[RefInfo]) -> String {
+    let mut refs: Vec<_> = refs
+        .iter()
+        .filter(|info| info.name.starts_with("refs/"))
+        .collect();
+    refs.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    let mut content = String::from("# pack-refs with: sorted \n");
+    for info in refs {
+        content.push_str(&format!("{} {}\n", info.oid, info.name));
+    }
+    content
+}
+
+/// Attributes of the file `name`, stored at `path` in the view directory.
+fn file_attr(name: &str, path: &str, size: u64) -> InodeAttributes {
+    view_attr(
+        name,
+        path,
+        size,
+        ContentType::File,
+        DEFAULT_FILE_PERMISSIONS,
+    )
+}
+
+fn view_attr(
+    name: &str,
+    path: &str,
+    size: u64,
+    kind: ContentType,
+    permissions: u16,
+) -> InodeAttributes {
+    InodeAttributes {
+        id: name.to_owned(),
+        commit_id: String::new(),
+        size,
+        size_known: true,
+        name: name.to_owned(),
+        kind,
+        path: path.to_owned(),
+        mtime: SystemTime::now(),
+        ctime: SystemTime::now(),
+        permissions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn entry(name: &str, id: &str, kind: ContentType) -> InodeAttributes {
+        InodeAttributes {
+            id: id.to_owned(),
+            ..view_attr(name, name, 0, kind, DEFAULT_FILE_PERMISSIONS)
+        }
+    }
+
+    #[test]
+    fn test_tree_content() {
+        let blob = "ce013625030ba8dba906f756967f9e9ca394464a";
+        let subtree = "7d4a466af82cd6857c85c0296d5c23fc68cba887";
+        assert_eq!(
+            object_id(
+                "tree",
+                &tree_content(&[entry("README", blob, ContentType::File)]).unwrap()
+            ),
+            subtree
+        );
+        // `a.txt` sorts before the directory `a`
+        let entries = [
+            entry("a", subtree, ContentType::Dir),
+            entry("a.txt", blob, ContentType::File),
+        ];
+        let content = tree_content(&entries).unwrap();
+        assert_eq!(
+            object_kind("af7eaa234978a8890d81054b93a854948a98481a", &content),
+            Some("tree")
+        );
+        assert_eq!(object_kind(blob, b"hello\n"), Some("blob"));
+        assert_eq!(object_kind(blob, b"hello"), None);
+        assert!(tree_content(&[entry("a", "not-an-id", ContentType::File)]).is_none());
+    }
+
+    #[test]
+    fn test_objects() {
+        let dir = std::env::temp_dir().join(format!("mega-fuse-git-{}", std::process::id()));
+        let head = "b6eb9ec1046d0e64adbcfdebe09d28eab43a94f9";
+        let refs = [
+            RefInfo {
+                name: "refs/tags/v1".to_owned(),
+                oid: head.to_owned(),
+            },
+            RefInfo {
+                name: "refs/heads/main".to_owned(),
+                oid: head.to_owned(),
+            },
+            RefInfo {
+                name: "HEAD".to_owned(),
+                oid: head.to_owned(),
+            },
+        ];
+        let mut view = GitView::new(&dir, "fuser", head, &refs, &HashMap::new()).unwrap();
+        let root = view.root();
+        let packed_refs = view.lookup_name(root, PACKED_REFS).unwrap();
+        let mut handle = view.open(packed_refs).unwrap().unwrap();
+        assert_eq!(
+            handle.read(0, 1000).unwrap(),
+            format!(
+                "# pack-refs with: sorted \n{0} refs/heads/main\n{0} refs/tags/v1\n",
+                head
+            )
+            .as_bytes()
+        );
+        let head_ino = view.lookup_name(root, "HEAD").unwrap();
+        let mut handle = view.open(head_ino).unwrap().unwrap();
+        assert_eq!(
+            handle.read(0, 100).unwrap(),
+            format!("{}\n", head).as_bytes()
+        );
+
+        let objects = view.lookup_name(root, "objects").unwrap();
+        assert!(view.lookup_name(objects, "zz").is_none());
+        let fan_out = view.lookup_name(objects, "ce").unwrap();
+        let name = "013625030ba8dba906f756967f9e9ca394464a";
+        assert!(view.lookup_name(fan_out, name).is_none());
+        let id = view.object_id(fan_out, name).unwrap();
+        let ino = view
+            .insert_object(fan_out, &id, "blob", b"hello\n")
+            .unwrap();
+        assert_eq!(view.lookup_name(fan_out, name), Some(ino));
+
+        let mut loose = Vec::new();
+        ZlibDecoder::new(File::open(dir.join("git/fuser").join(object_path(&id))).unwrap())
+            .read_to_end(&mut loose)
+            .unwrap();
+        assert_eq!(loose, b"blob 6\0hello\n");
+
+        // Loose objects outlive the mount
+        let mut view = GitView::new(&dir, "fuser", head, &[], &HashMap::new()).unwrap();
+        let objects = view.lookup_name(view.root(), "objects").unwrap();
+        let fan_out = view.lookup_name(objects, "ce").unwrap();
+        assert!(view.lookup_name(fan_out, name).is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
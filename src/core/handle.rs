@@ -0,0 +1,78 @@
+//! Per-open state of regular files.
+use std::{
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+};
+
+/// Bytes the kernel is advised to read ahead once sequential access is seen.
+const READ_AHEAD_WINDOW: i64 = 1 << 20; // 1 MiB
+
+/// An opened file, created by `open` and dropped by `release`. The handle pins
+/// the object id and the cached blob seen at open time, so later changes of
+/// the inode never affect data served through an existing handle.
+#[derive(Debug)]
+pub struct FileHandle {
+    /// Inode the handle was opened on
+    pub ino: u64,
+    /// Object id of the content pinned at open time
+    pub id: String,
+    /// The cached blob backing this handle
+    file: File,
+    /// Size of the cached blob
+    pub size: u64,
+    /// Offset right after the previous read, to detect sequential access
+    next_offset: u64,
+    /// Whether a read through this handle was written to the access log
+    pub logged_read: bool,
+}
+
+impl FileHandle {
+    /// Wrap a cached blob opened for inode `ino` with object id `id`.
+    pub fn new(ino: u64, id: String, file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(FileHandle {
+            ino,
+            id,
+            file,
+            size,
+            next_offset: 0,
+            logged_read: false,
+        })
+    }
+
+    /// Read at most `size` bytes starting from `offset`.
+    pub fn read(&mut self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let len = (size as u64).min(self.size.saturating_sub(offset)) as usize;
+        let mut buf = vec![0; len];
+        let mut filled = 0;
+        while filled < len {
+            match self
+                .file
+                .read_at(&mut buf[filled..], offset + filled as u64)?
+            {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        buf.truncate(filled);
+
+        if offset == self.next_offset && offset + (filled as u64) < self.size {
+            self.read_ahead(offset + filled as u64);
+        }
+        self.next_offset = offset + filled as u64;
+        Ok(buf)
+    }
+
+    fn read_ahead(&self, offset: u64) {
+        // Only a hint: failing to advise is harmless
+        unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                offset as libc::off_t,
+                READ_AHEAD_WINDOW,
+                libc::POSIX_FADV_WILLNEED,
+            );
+        }
+    }
+}
@@ -1,19 +1,21 @@
 use std::{
     convert::From,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicU32, AtomicU64, Ordering},
     time::{Duration, SystemTime},
 };
 
 use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-const BLOCK_SIZE: u32 = 4096;
+use crate::config::Ownership;
+
+pub const BLOCK_SIZE: u32 = 4096;
 const RDEV: u32 = 0;
 const FLAGS: u32 = 0;
 const DEFAULT_HARD_LINKS: u32 = 1;
-const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
-const DEFAULT_DIR_PERMISSIONS: u16 = 0o755;
+pub const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
+pub const DEFAULT_DIR_PERMISSIONS: u16 = 0o755;
 // static GID: AtomicU32 = AtomicU32::new(1000);
 // static UID: AtomicU32 = AtomicU32::new(1000);
 // pub fn init_gu_id(gid: u32, uid: u32) {
@@ -29,6 +31,7 @@ const DEFAULT_DIR_PERMISSIONS: u16 = 0o755;
 //     UID.load(std::sync::atomic::Ordering::Acquire)
 // }
 
+/// An entry of a tree listing returned by the Mega server
 #[derive(Debug, Deserialize)]
 pub struct Object {
     id: String,
@@ -36,17 +39,50 @@ pub struct Object {
     path: PathBuf,
     content_type: ContentType,
     commit_date: String,
+    /// Size of a file, if the server tells it
+    #[serde(default)]
+    size: Option<u64>,
     // Field below are ignored for now
     under_repo: bool,
     commit_msg: String,
     commit_id: String,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+impl Object {
+    /// Object id, the git hash of the content
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Name of the entry in its directory
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Path of the entry on the server, under the repository root
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the entry is a file or a directory
+    pub fn content_type(&self) -> &ContentType {
+        &self.content_type
+    }
+
+    /// Last commit touching the entry
+    pub fn commit_id(&self) -> &str {
+        &self.commit_id
+    }
+}
+
+/// Kind of an entry of the tree
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum ContentType {
-    #[serde(rename(deserialize = "file"))]
+    /// Regular file
+    #[serde(rename = "file")]
     File,
-    #[serde(rename(deserialize = "directory"))]
+    /// Directory
+    #[serde(rename = "directory")]
     Dir,
 }
 
@@ -59,8 +95,10 @@ impl From<&ContentType> for FileType {
     }
 }
 
+/// Entries of a directory, as returned by the tree endpoint
 #[derive(Debug, Deserialize)]
 pub struct Objects {
+    /// The entries
     #[serde(rename(deserialize = "items"))]
     pub data: Vec<Object>,
 }
@@ -71,13 +109,20 @@ fn alloc_ino() -> u64 {
     INO_ALLOCATOR.fetch_add(1, Ordering::SeqCst)
 }
 
+/// Make sure inode numbers up to `ino` are never allocated again, used when
+/// inodes are restored with their previous numbers.
+pub fn reserve_ino(ino: u64) {
+    INO_ALLOCATOR.fetch_max(ino + 1, Ordering::SeqCst);
+}
+
 #[derive(Debug)]
 pub struct Inode {
     pub ino: u64,
     pub parent_ino: u64,
     pub children_ino: Vec<u64>,
     pub attr: InodeAttributes,
-    pub content: Option<String>,
+    /// Object id of the content the kernel may still hold in its page cache
+    pub cached_id: Option<String>,
 }
 
 impl Inode {
@@ -88,7 +133,19 @@ impl Inode {
             parent_ino,
             children_ino: Vec::new(),
             attr,
-            content: None,
+            cached_id: None,
+        }
+    }
+
+    /// Restore an inode with a previously allocated inode number.
+    pub fn with_ino(ino: u64, parent_ino: u64, attr: InodeAttributes) -> Self {
+        reserve_ino(ino);
+        Self {
+            ino,
+            parent_ino,
+            children_ino: Vec::new(),
+            attr,
+            cached_id: None,
         }
     }
 
@@ -106,8 +163,14 @@ impl Inode {
         self.children_ino.remove(index);
     }
 
-    pub fn file_attr(&self, uid: u32, gid: u32) -> FileAttr {
+    /// Attributes reported to the kernel, owned and masked as configured by
+    /// `ownership`.
+    pub fn file_attr(&self, ownership: &Ownership) -> FileAttr {
         let attrs = &self.attr;
+        let mask = match attrs.kind {
+            ContentType::Dir => ownership.dmask,
+            ContentType::File => ownership.fmask,
+        };
         FileAttr {
             ino: self.ino,
             size: attrs.size,
@@ -120,19 +183,44 @@ impl Inode {
                 ContentType::Dir => FileType::Directory,
                 ContentType::File => FileType::RegularFile,
             },
-            perm: attrs.permissions,
+            perm: attrs.permissions & !mask,
             nlink: DEFAULT_HARD_LINKS,
-            uid,
-            gid,
+            uid: ownership.uid,
+            gid: ownership.gid,
             rdev: RDEV,
             blksize: BLOCK_SIZE,
             flags: FLAGS,
         }
     }
+
+    /// Check an `access(2)` style `mask` against the permissions this inode
+    /// reports under `ownership` to the caller identified by `uid` and the
+    /// groups `gids` it belongs to, supplementary ones included.
+    pub fn permits(&self, ownership: &Ownership, uid: u32, gids: &[u32], mask: i32) -> bool {
+        let attr = self.file_attr(ownership);
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        if uid == 0 {
+            // root may read and write anything, but only execute what is
+            // executable for someone
+            let any_exec = attr.perm & 0o111 != 0 || attr.kind == FileType::Directory;
+            return mask & libc::X_OK as u16 == 0 || any_exec;
+        }
+        let granted = if attr.uid == uid {
+            attr.perm >> 6
+        } else if gids.contains(&attr.gid) {
+            attr.perm >> 3
+        } else {
+            attr.perm
+        } & 0o7;
+        granted & mask == mask
+    }
+
     pub fn root_node(fs_name: &str) -> Inode {
         let attr = InodeAttributes {
             id: fs_name.to_string(),
+            commit_id: String::new(),
             size: BLOCK_SIZE as u64,
+            size_known: true,
             name: fs_name.to_string(),
             path: "".to_owned(),
             kind: ContentType::Dir,
@@ -145,20 +233,35 @@ impl Inode {
             parent_ino: FUSE_ROOT_ID,
             children_ino: Vec::new(),
             attr,
-            content: None,
+            cached_id: None,
         }
     }
 }
 
-#[derive(Debug)]
+/// Metadata of an inode of the mounted tree
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InodeAttributes {
+    /// Object id
     pub id: String,
+    /// Last commit touching this object
+    pub commit_id: String,
+    /// Size in bytes, 0 until known
     pub size: u64,
+    /// Whether `size` is the actual size: given by the tree listing, or
+    /// learnt from the content once retrieved
+    #[serde(default)]
+    pub size_known: bool,
+    /// Name in the parent directory
     pub name: String,
+    /// File or directory
     pub kind: ContentType,
+    /// Path relative to the repository root
     pub path: String,
+    /// Date of the last commit touching this object
     pub mtime: SystemTime,
+    /// Same as `mtime`
     pub ctime: SystemTime,
+    /// Permission bits before masking
     pub permissions: u16,
 }
 
@@ -171,10 +274,13 @@ impl From<Object> for InodeAttributes {
             ContentType::Dir => DEFAULT_DIR_PERMISSIONS,
             ContentType::File => DEFAULT_FILE_PERMISSIONS,
         };
+        let size_known = object.size.is_some() || object.content_type == ContentType::Dir;
         Self {
             kind: object.content_type,
             id: object.id,
-            size: 0,
+            commit_id: object.commit_id,
+            size: object.size.unwrap_or_default(),
+            size_known,
             name: object.name,
             path: object
                 .path
@@ -206,6 +312,7 @@ mod tests {
          "name":"deny.toml",
          "path":"/projects/fuser/deny.toml",
          "content_type":"file",
+         "size":1570,
          "under_repo":true,
          "commit_msg":"",
          "commit_date":"1701057603",
@@ -225,6 +332,60 @@ mod tests {
 }
 "#;
         let objects: Objects = serde_json::from_str(tree_response).unwrap();
-        dbg!(objects);
+        let attrs: Vec<_> = objects
+            .data
+            .into_iter()
+            .map(InodeAttributes::from)
+            .collect();
+        assert_eq!((attrs[0].size, attrs[0].size_known), (1570, true));
+        // Directories have no size to learn
+        assert!(attrs[1].size_known);
+    }
+
+    #[test]
+    fn test_permits() {
+        let file = Inode::new(
+            FUSE_ROOT_ID,
+            InodeAttributes {
+                id: String::new(),
+                commit_id: String::new(),
+                size: 0,
+                size_known: true,
+                name: "deny.toml".to_owned(),
+                kind: ContentType::File,
+                path: "deny.toml".to_owned(),
+                mtime: SystemTime::now(),
+                ctime: SystemTime::now(),
+                permissions: DEFAULT_FILE_PERMISSIONS,
+            },
+        );
+        let ownership = Ownership {
+            uid: 1000,
+            gid: 1000,
+            fmask: 0,
+            dmask: 0,
+        };
+        assert!(file.permits(&ownership, 1000, &[1000], libc::F_OK));
+        assert!(file.permits(&ownership, 1000, &[1000], libc::R_OK));
+        assert!(!file.permits(&ownership, 1000, &[1000], libc::X_OK));
+        assert!(!file.permits(&ownership, 0, &[0], libc::X_OK));
+
+        let dir = Inode::root_node("fuser");
+        assert!(dir.permits(&ownership, 1000, &[1000], libc::R_OK | libc::X_OK));
+        assert!(dir.permits(&ownership, 0, &[0], libc::X_OK));
+
+        // Other accounts get the permissions of others, minus the masks
+        let ownership = Ownership {
+            fmask: 0o077,
+            dmask: 0o027,
+            ..ownership
+        };
+        assert_eq!(file.file_attr(&ownership).perm, 0o600);
+        assert_eq!(file.file_attr(&ownership).uid, 1000);
+        assert!(!file.permits(&ownership, 1001, &[1000], libc::R_OK));
+        assert!(dir.permits(&ownership, 1001, &[1000], libc::R_OK | libc::X_OK));
+        assert!(!dir.permits(&ownership, 1001, &[1001], libc::R_OK));
+        // Through a supplementary group
+        assert!(dir.permits(&ownership, 1001, &[1001, 1000], libc::R_OK));
     }
 }
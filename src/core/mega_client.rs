@@ -1,16 +1,115 @@
-use std::{path::PathBuf, str, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bytes::Bytes;
-use fuser::ReplyOpen;
-use http_body_util::{BodyExt, Empty};
-use hyper::{client::conn::http1::SendRequest, Request};
-use hyper_util::rt::TokioIo;
-use tokio::{net::TcpStream, runtime, runtime::Runtime};
-use tracing::info;
+use http_body_util::Empty;
+use hyper::{Request, StatusCode};
+use tokio::{runtime, runtime::Runtime};
+use tracing::{debug, info, warn};
 
-use super::inode::Objects;
-use crate::config::ValidatedConfig;
+use super::{
+    api::{
+        BlobRequest, CommitInfo, CommitRequest, Endpoint, ObjectRequest, RefInfo, RefsRequest,
+        SubtreesRequest, TreeRequest,
+    },
+    cache::blob_id,
+    inode::{ContentType, Object, Objects},
+    transport::{HttpStatus, Transport},
+};
+use crate::config::{ApiSettings, ValidatedConfig};
+
+/// Downloads of a blob not matching its object id are attempted this many
+/// times before giving up.
+const FETCH_ATTEMPTS: usize = 3;
+/// Directories listed at once when the server cannot batch tree requests
+const MAX_CONCURRENT_TREES: usize = 32;
+/// How often the caller of a request is checked for interruption
+const CALLER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A request abandoned because the process it was made for was interrupted
+/// or exited
+#[derive(Debug)]
+pub struct Interrupted {
+    /// The process the request was made for
+    pub pid: u32,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request interrupted: process {} has a signal or exited",
+            self.pid
+        )
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Signals ignored unless handled, pending all the time for build tools
+const DEFAULT_IGNORED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGCHLD, libc::SIGWINCH, libc::SIGURG, libc::SIGCONT];
+
+/// Whether process `pid` exited or has a signal pending that is neither
+/// blocked nor ignored, explicitly or by default. This approximates the FUSE
+/// interrupts fuser does not pass on, checked by polling: a signal blocked
+/// by the process, or taken between two checks, goes unnoticed, and so does
+/// a handled `SIGCHLD`. Pid 0, a process of another pid namespace, is never
+/// interrupted.
+fn interrupted(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => signaled(&status),
+        Err(_) => true,
+    }
+}
+
+/// `interrupted`, given the content of `/proc/<pid>/status`.
+fn signaled(status: &str) -> bool {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(str::trim)
+    };
+    if field("State:").is_some_and(|state| state.starts_with(['Z', 'X'])) {
+        return true;
+    }
+    let mask = |name: &str| {
+        field(name)
+            .and_then(|mask| u64::from_str_radix(mask, 16).ok())
+            .unwrap_or(0)
+    };
+    let default_ignored = DEFAULT_IGNORED_SIGNALS
+        .iter()
+        .fold(0, |mask, signal| mask | 1 << (signal - 1));
+    let pending = mask("SigPnd:") | mask("ShdPnd:");
+    pending & !(mask("SigBlk:") | mask("SigIgn:") | default_ignored) != 0
+}
+
+/// Check that `content` is the blob with object id `id`, hashed the way git
+/// hashes its objects. Truncated transfers and corrupting proxies fail here.
+pub(crate) fn verify_blob(id: &str, content: &[u8]) -> Result<()> {
+    let actual = blob_id(content);
+    if !actual.eq_ignore_ascii_case(id) {
+        bail!(
+            "blob {} does not match its content ({} bytes hashing to {})",
+            id,
+            content.len(),
+            actual
+        );
+    }
+    Ok(())
+}
 
 /// MegaClient is used to handling connection details.
 /// Adapting the remote server's asynchronous nature, this client is also
@@ -19,7 +118,13 @@ use crate::config::ValidatedConfig;
 #[derive(Debug)]
 pub struct MegaClient {
     rt: Arc<Runtime>,
-    sender: SendRequest<Empty<Bytes>>,
+    addr: String,
+    api: ApiSettings,
+    transport: Transport,
+    /// Cleared once the server fails a batched tree request
+    batch_trees: bool,
+    /// Process the requests are made for, abandoned once it is interrupted
+    caller: Option<u32>,
 }
 
 impl MegaClient {
@@ -30,24 +135,7 @@ impl MegaClient {
             .enable_all()
             .build()
             .unwrap();
-        let rt = Arc::new(rt);
-
-        let addr = &config.server_url;
-        let stream = rt.block_on(TcpStream::connect(addr))?;
-        let io = TokioIo::new(stream);
-
-        let (sender, conn) = rt.block_on(hyper::client::conn::http1::handshake::<
-            TokioIo<tokio::net::TcpStream>,
-            Empty<Bytes>,
-        >(io))?;
-
-        rt.spawn(async move {
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
-            }
-        });
-
-        Ok(MegaClient { rt, sender })
+        Self::from_customized_runtime(Arc::new(rt), config)
     }
     /// Creates a MegaClient from a given runtime. The reason it exists instead
     /// of providing a default `Runtime` is to enable customization on
@@ -56,45 +144,85 @@ impl MegaClient {
         rt: Arc<Runtime>,
         config: &ValidatedConfig,
     ) -> Result<MegaClient> {
-        let addr = &config.server_url;
-        let stream = rt.block_on(TcpStream::connect(addr))?;
-        let io = TokioIo::new(stream);
+        let addr = config.server_url.clone();
+        let transport = rt.block_on(Transport::connect(
+            &addr,
+            config.http_version,
+            config.compression,
+            config.request_timeouts,
+        ))?;
+        Ok(MegaClient {
+            rt,
+            addr,
+            api: config.api.clone(),
+            transport,
+            batch_trees: true,
+            caller: None,
+        })
+    }
 
-        let (sender, conn) = rt.block_on(hyper::client::conn::http1::handshake::<
-            TokioIo<tokio::net::TcpStream>,
-            Empty<Bytes>,
-        >(io))?;
+    /// The runtime requests of this MegaClient are executed on.
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.rt
+    }
 
-        rt.spawn(async move {
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
-            }
-        });
-        Ok(MegaClient { rt, sender })
+    /// Address of the Mega server this MegaClient is connected to.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Where the API lives on the server this MegaClient is connected to.
+    pub fn api(&self) -> &ApiSettings {
+        &self.api
+    }
+
+    /// Protocol spoken with the server, `HTTP/2` or `HTTP/1.1`.
+    pub fn protocol(&self) -> &'static str {
+        self.transport.protocol()
+    }
+
+    /// Connections to the server, shared with the prefetch workers.
+    pub(crate) fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Make the following requests on behalf of process `pid`: they fail with
+    /// `Interrupted` once it gets a signal or exits. `None` for requests of
+    /// the mount itself, and so is pid 0: FUSE reports callers outside of the
+    /// pid namespace of the mount as such.
+    pub fn set_caller(&mut self, pid: Option<u32>) {
+        self.caller = pid.filter(|pid| *pid != 0);
     }
 
     /// Send a `Request` to the server pointed by this MegaClient, retrieve the
-    /// content in response to comprise a `String`.
-    pub fn request(&mut self, req: Request<Empty<Bytes>>) -> Result<String> {
-        let mut response = self.rt.block_on(self.sender.send_request(req))?;
-
-        let output = self.rt.block_on(async {
-            let mut output = String::new();
-            while let Some(next) = response.frame().await {
-                let frame = next.unwrap();
-                if let Some(chunk) = frame.data_ref() {
-                    let c = chunk.as_ref();
-                    output.push_str(str::from_utf8(c).unwrap());
-                    // io::stdout().write_all(&chunk).await?;
+    /// raw content in response.
+    pub fn request_bytes(&mut self, req: Request<Empty<Bytes>>) -> Result<Bytes> {
+        let send = self.transport.send(req);
+        let Some(pid) = self.caller else {
+            return self.rt.block_on(send);
+        };
+        self.rt.block_on(async {
+            let mut send = pin!(send);
+            loop {
+                if let Ok(result) = tokio::time::timeout(CALLER_POLL_INTERVAL, &mut send).await {
+                    return result;
+                }
+                // Dropping the request cancels the download
+                if interrupted(pid) {
+                    return Err(Interrupted { pid }.into());
                 }
             }
-            output
-        });
+        })
+    }
 
-        Ok(output)
+    /// Send a `Request` to the server pointed by this MegaClient, retrieve the
+    /// content in response to comprise a `String`.
+    pub fn request(&mut self, req: Request<Empty<Bytes>>) -> Result<String> {
+        let output = self.request_bytes(req)?;
+        Ok(String::from_utf8(output.to_vec())?)
     }
 
-    fn form_request_to(target: &str) -> Request<Empty<Bytes>> {
+    pub(crate) fn form_request_to(target: &str) -> Request<Empty<Bytes>> {
         Request::builder()
             .method("GET")
             .uri(target)
@@ -102,44 +230,194 @@ impl MegaClient {
             .unwrap()
     }
 
+    /// Send `endpoint` to the server pointed by this MegaClient and decode
+    /// the response.
+    pub fn call<E: Endpoint>(&mut self, endpoint: &E) -> Result<E::Response> {
+        let req = Self::form_request_to(&endpoint.uri(&self.api));
+        debug!("Sending request: {:?}", req);
+        E::parse(self.request_bytes(req)?)
+    }
+
     /// Send request with dedicated API and repo_path
-    pub fn request_base_tree(&mut self, target: &str) -> Objects {
-        let target = format!("/api/v1/tree?repo_path=/projects/{}", target);
-        let req = Self::form_request_to(&target);
-        info!("Sending request to retrieve directory: {:?}", req);
-        let response = self.request(req).unwrap();
-        serde_json::from_str(&response).unwrap()
+    pub fn request_base_tree(&mut self, target: &str) -> Result<Objects> {
+        self.call(&TreeRequest::root(target))
     }
 
     /// Send request with dedicated API, object_id and repo_path
-    pub fn request_sub_tree_with_id(&mut self, target: &str, id: &str) -> Objects {
-        let target = format!(
-            "/api/v1/tree?object_id={}&repo_path=/projects/{}",
-            id, target
-        );
-        let req = Self::form_request_to(&target);
-        info!("Sending request to retrieve directory: {:?}", req);
-        let response = self.request(req).unwrap();
-        serde_json::from_str(&response).unwrap()
+    pub fn request_sub_tree_with_id(&mut self, target: &str, id: &str) -> Result<Objects> {
+        self.call(&TreeRequest::with_id(target, id))
     }
 
-    /// Retrieve actual file content
-    pub fn request_file_content(&mut self, target: &str, id: &str) -> String {
-        let target = format!(
-            "/api/v1/object?object_id={}&repo_path=/projects/{}",
-            id, target
-        );
-        let req = Self::form_request_to(&target);
-        info!("Sending request to retrieve file content: {:?}", req);
-        self.request(req).unwrap()
+    /// Branches and tags of repo `target`.
+    pub fn request_refs(&mut self, target: &str) -> Result<Vec<RefInfo>> {
+        self.call(&RefsRequest {
+            repo: target.to_owned(),
+        })
+    }
+
+    /// Latest commit of repo `target`, the one its tree is listed at.
+    pub fn request_head_commit(&mut self, target: &str) -> Result<CommitInfo> {
+        self.call(&CommitRequest {
+            repo: target.to_owned(),
+            path: None,
+        })
+    }
+
+    /// List the directories `dirs`, object ids along with their path in the
+    /// repository, and their subdirectories down to `depth` levels, keyed by
+    /// object id. Every directory of `dirs` is listed, subdirectories only if
+    /// `descend` accepts their path. Takes a single request if the server
+    /// supports batches, concurrent requests per directory otherwise.
+    pub fn request_sub_trees(
+        &mut self,
+        target: &str,
+        dirs: &[(String, PathBuf)],
+        depth: u32,
+        descend: impl Fn(&Path) -> bool,
+    ) -> Result<HashMap<String, Vec<Object>>> {
+        if self.batch_trees {
+            let request = SubtreesRequest {
+                repo: target.to_owned(),
+                object_ids: dirs.iter().map(|(id, _)| id.clone()).collect(),
+                depth,
+            };
+            match self.call(&request) {
+                Ok(subtrees) => {
+                    let mut trees: HashMap<_, _> = subtrees
+                        .data
+                        .into_iter()
+                        .map(|subtree| (subtree.object_id, subtree.data))
+                        .collect();
+                    let missing: Vec<_> = dirs
+                        .iter()
+                        .filter(|(id, _)| !trees.contains_key(id))
+                        .cloned()
+                        .collect();
+                    if !missing.is_empty() {
+                        trees.extend(self.request_each_sub_tree(target, &missing, 1, &descend)?);
+                    }
+                    return Ok(trees);
+                }
+                // Only a server without the endpoint is worth the fallback
+                Err(err) if batches_unsupported(&err) => {
+                    info!("Tree requests not batched by {}: {:#}", self.addr, err);
+                    self.batch_trees = false;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.request_each_sub_tree(target, dirs, depth, &descend)
+    }
+
+    /// `request_sub_trees` one directory per request, level by level, up to
+    /// `MAX_CONCURRENT_TREES` requests at a time.
+    fn request_each_sub_tree(
+        &self,
+        target: &str,
+        dirs: &[(String, PathBuf)],
+        depth: u32,
+        descend: &impl Fn(&Path) -> bool,
+    ) -> Result<HashMap<String, Vec<Object>>> {
+        let mut trees = HashMap::new();
+        let mut level = dirs.to_vec();
+        for _ in 0..depth.max(1) {
+            // Identical subtrees share their object id
+            level.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            level.dedup_by(|(a, _), (b, _)| a == b);
+            level.retain(|(id, _)| !trees.contains_key(id));
+            let mut next = Vec::new();
+            for chunk in level.chunks(MAX_CONCURRENT_TREES) {
+                let requests: Vec<_> = chunk
+                    .iter()
+                    .map(|(id, path)| {
+                        let transport = self.transport.clone();
+                        let uri = TreeRequest::with_id(target, id).uri(&self.api);
+                        let request = self.rt.spawn(async move {
+                            let body = transport.send(Self::form_request_to(&uri)).await?;
+                            TreeRequest::parse(body)
+                        });
+                        (id.clone(), path, request)
+                    })
+                    .collect();
+                for (id, path, request) in requests {
+                    let objects = self.rt.block_on(request)??;
+                    next.extend(
+                        objects
+                            .data
+                            .iter()
+                            .filter(|object| object.content_type() == &ContentType::Dir)
+                            .map(|object| (object.id().to_owned(), path.join(object.name())))
+                            .filter(|(_, path)| descend(path)),
+                    );
+                    trees.insert(id, objects.data);
+                }
+            }
+            level = next;
+        }
+        Ok(trees)
+    }
+
+    /// Look up the entry at `path`, relative to the root of repo `target`, by
+    /// listing its parent directory by path instead of walking the tree from
+    /// the root. `None` if there is no such entry.
+    pub fn request_object_by_path(&mut self, target: &str, path: &Path) -> Result<Option<Object>> {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            bail!("{:?} does not name an entry of the tree", path);
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let objects = self.call(&TreeRequest::at_path(target, parent))?;
+        Ok(objects
+            .data
+            .into_iter()
+            .find(|object| object.name() == name))
+    }
+
+    /// Retrieve the raw content of object `id` of any type, unverified: the
+    /// caller finds its type by hashing it.
+    pub fn request_object(&mut self, target: &str, id: &str) -> Result<Bytes> {
+        self.call(&ObjectRequest::new(target, id))
+    }
+
+    /// Retrieve actual file content, verified against its object id. Content
+    /// which does not match is requested again, up to `FETCH_ATTEMPTS` times.
+    pub fn request_file_content(&mut self, id: &str) -> Result<Bytes> {
+        let request = BlobRequest {
+            object_id: id.to_owned(),
+        };
+        for attempt in 1..=FETCH_ATTEMPTS {
+            let content = self.call(&request)?;
+            match verify_blob(id, &content) {
+                Ok(()) => return Ok(content),
+                Err(err) => warn!("attempt {}/{}: {}", attempt, FETCH_ATTEMPTS, err),
+            }
+        }
+        bail!(
+            "blob {} is still corrupted after {} attempts",
+            id,
+            FETCH_ATTEMPTS
+        )
     }
 }
 
+/// Whether `err` tells the server has no batched tree endpoint, rather than
+/// the request failing.
+fn batches_unsupported(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<HttpStatus>().is_some_and(|err| {
+        [
+            StatusCode::NOT_FOUND,
+            StatusCode::METHOD_NOT_ALLOWED,
+            StatusCode::NOT_IMPLEMENTED,
+        ]
+        .contains(&err.status)
+    })
+}
+
 #[cfg(test)]
-mod tests {
-    use std::path::PathBuf;
+pub(crate) mod tests {
+    use std::{path::PathBuf, time::Duration};
 
     use super::*;
+    use crate::{cli::PrefetchTrigger, config::PrefetchSettings, core::sparse::SparseProfile};
 
     #[test]
     fn test_create_mega_client() {
@@ -152,15 +430,49 @@ mod tests {
         assert!(MegaClient::from_customized_runtime(Arc::new(rt), &config).is_ok());
     }
 
-    fn create_sample_config() -> ValidatedConfig {
+    pub(crate) fn create_sample_config() -> ValidatedConfig {
         ValidatedConfig {
             mount_point: PathBuf::from("/tmp"),
             cache_dir: PathBuf::from("/tmp"),
-            log_dir: PathBuf::from("/tmp"),
+            log_dir: Some(PathBuf::from("/tmp")),
+            access_log: false,
             server_url: String::from("localhost:8000"),
+            api: ApiSettings::default(),
+            http_version: crate::cli::HttpVersion::Auto,
+            compression: crate::cli::Compression::Auto,
+            request_timeouts: crate::config::RequestTimeouts::default(),
+            offline: false,
+            direct_io: false,
+            git_view: false,
+            ownership: crate::config::Ownership {
+                uid: 1000,
+                gid: 1000,
+                fmask: 0,
+                dmask: 0,
+            },
+            mount_options: Vec::new(),
+            attr_timeout: Duration::from_secs(1),
+            entry_timeout: Duration::from_secs(1),
+            negative_timeout: Duration::ZERO,
+            prefetch: PrefetchSettings {
+                concurrency: 0,
+                limit: 0,
+                trigger: PrefetchTrigger::None,
+            },
+            sparse: SparseProfile::default(),
+            metrics_addr: None,
         }
     }
 
+    #[test]
+    fn test_verify_blob() {
+        let id = "ce013625030ba8dba906f756967f9e9ca394464a";
+        assert!(verify_blob(id, b"hello\n").is_ok());
+        assert!(verify_blob(&id.to_uppercase(), b"hello\n").is_ok());
+        // Truncated
+        assert!(verify_blob(id, b"hell").is_err());
+    }
+
     fn create_mega_client() -> MegaClient {
         let rt = runtime::Builder::new_multi_thread()
             .worker_threads(10)
@@ -171,6 +483,140 @@ mod tests {
         MegaClient::from_customized_runtime(Arc::new(rt), &config).unwrap()
     }
 
+    /// A directory listing with a subdirectory `sub` of object id `sub_id`
+    fn listing(sub_id: &str) -> String {
+        format!(
+            r#"{{"items":[{{"id":"{}","name":"sub","path":"/projects/fuser/sub",
+            "content_type":"directory","under_repo":true,"commit_msg":"",
+            "commit_date":"1701057603","commit_id":"b6eb9ec1"}}]}}"#,
+            sub_id
+        )
+    }
+
+    #[test]
+    fn test_request_sub_trees_fallback() {
+        use std::convert::Infallible;
+
+        use http_body_util::Full;
+        use hyper::{service::service_fn, Response, StatusCode};
+        use hyper_util::rt::TokioIo;
+
+        let rt = Arc::new(
+            runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap(),
+        );
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let mut config = create_sample_config();
+        config.server_url = listener.local_addr().unwrap().to_string();
+        config.http_version = crate::cli::HttpVersion::Http1;
+        rt.spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|req: hyper::Request<hyper::body::Incoming>| async move {
+                    let query = req.uri().query().unwrap_or_default().to_owned();
+                    let response = match req.uri().path() {
+                        // A server without batches
+                        "/api/v1/tree/batch" => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Full::new(Bytes::from("not found"))),
+                        _ if query.contains("object_id=d0") => {
+                            Response::builder().body(Full::new(Bytes::from(listing("d1"))))
+                        }
+                        _ => Response::builder().body(Full::new(Bytes::from(listing("d2")))),
+                    };
+                    Ok::<_, Infallible>(response.unwrap())
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let mut mc = MegaClient::from_customized_runtime(rt, &config).unwrap();
+        let d0 = ("d0".to_owned(), PathBuf::from("src"));
+        let trees = mc
+            .request_sub_trees("fuser", &[d0.clone(), d0.clone()], 2, |_| true)
+            .unwrap();
+        assert!(!mc.batch_trees);
+        // Two levels: the directory and its subdirectory, not deeper
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees["d0"][0].id(), "d1");
+        assert_eq!(trees["d1"][0].id(), "d2");
+        // Excluded subdirectories are not listed
+        let trees = mc
+            .request_sub_trees("fuser", &[d0], 2, |path| path != Path::new("src/sub"))
+            .unwrap();
+        assert_eq!(trees.len(), 1);
+
+        // A failing server is not taken for one without batches
+        let status = |status| {
+            anyhow::Error::from(HttpStatus {
+                endpoint: "tree/batch".to_owned(),
+                status,
+            })
+        };
+        assert!(batches_unsupported(&status(StatusCode::METHOD_NOT_ALLOWED)));
+        assert!(!batches_unsupported(&status(
+            StatusCode::INTERNAL_SERVER_ERROR
+        )));
+    }
+
+    #[test]
+    fn test_interrupted() {
+        assert!(!interrupted(std::process::id()));
+        // Beyond the largest pid_max
+        assert!(interrupted(u32::MAX));
+        // Unknown in this pid namespace
+        assert!(!interrupted(0));
+
+        let status = |pending: &str, ignored: &str| {
+            format!(
+                "State:\tS (sleeping)\nShdPnd:\t0000000000000000\nSigPnd:\t{}\n\
+                 SigBlk:\t0000000000000000\nSigIgn:\t{}\n",
+                pending, ignored
+            )
+        };
+        // SIGINT
+        assert!(signaled(&status("0000000000000002", "0000000000000000")));
+        assert!(!signaled(&status("0000000000000002", "0000000000000002")));
+        // SIGCHLD and SIGWINCH, ignored by default
+        assert!(!signaled(&status("0000000008010000", "0000000000000000")));
+    }
+
+    #[test]
+    fn test_request_interrupted() {
+        // Accepts connections, never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = create_sample_config();
+        config.server_url = listener.local_addr().unwrap().to_string();
+        config.http_version = crate::cli::HttpVersion::Http1;
+        std::thread::spawn(move || {
+            let mut streams = Vec::new();
+            for stream in listener.incoming() {
+                streams.push(stream.unwrap());
+            }
+        });
+        let mut mc = create_customized_client(&config);
+        mc.set_caller(Some(0));
+        assert!(mc.caller.is_none());
+        mc.set_caller(Some(u32::MAX));
+        let err = mc.request_object("fuser", "d2c7").unwrap_err();
+        assert_eq!(err.downcast_ref::<Interrupted>().unwrap().pid, u32::MAX);
+    }
+
+    fn create_customized_client(config: &ValidatedConfig) -> MegaClient {
+        let rt = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        MegaClient::from_customized_runtime(Arc::new(rt), config).unwrap()
+    }
+
     /// This test requires a working mega server
     #[test]
     fn test_mage_client_make_request() {
@@ -0,0 +1,362 @@
+//! Process wide metrics of the mount: latency of FUSE operations and HTTP
+//! requests, cache efficiency and bytes transferred. They are exported in
+//! Prometheus text format over a local HTTP endpoint and summarized in the log
+//! on unmount.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+/// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1, 0.5, 2.5, 10.0,
+];
+
+static METRICS: Metrics = Metrics::new();
+
+/// The metrics of this process.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// Measure the latency of the FUSE operation `op` until the returned timer
+/// drops.
+pub fn time_op(op: &'static str) -> OpTimer {
+    OpTimer {
+        op,
+        start: Instant::now(),
+    }
+}
+
+/// Records the latency of a FUSE operation when dropped
+#[derive(Debug)]
+pub struct OpTimer {
+    op: &'static str,
+    start: Instant,
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        METRICS.record_op(self.op, self.start.elapsed());
+    }
+}
+
+/// Latency distribution over `LATENCY_BUCKETS`
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, the last one counts those above every bound
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    /// Upper bound of the bucket holding the `quantile`, `None` if above every
+    /// bound.
+    fn quantile(&self, quantile: f64) -> Option<f64> {
+        let rank = (self.count as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            seen += bucket;
+            if seen >= rank {
+                return Some(bound);
+            }
+        }
+        None
+    }
+
+    /// Append the samples of the histogram `name` labelled with `labels`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            cumulative += bucket;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// Counters and histograms of the mount
+#[derive(Debug)]
+pub struct Metrics {
+    /// Latency by FUSE operation
+    ops: Mutex<BTreeMap<&'static str, Histogram>>,
+    /// Latency by endpoint and status of HTTP requests to the Mega server
+    http: Mutex<BTreeMap<(String, String), Histogram>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    /// Bytes of response bodies received from the Mega server
+    downloaded_bytes: AtomicU64,
+    /// Bytes of file content served to the kernel
+    read_bytes: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            ops: Mutex::new(BTreeMap::new()),
+            http: Mutex::new(BTreeMap::new()),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Record one FUSE operation `op` which took `latency`.
+    pub fn record_op(&self, op: &'static str, latency: Duration) {
+        self.ops
+            .lock()
+            .unwrap()
+            .entry(op)
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Record one HTTP request to `endpoint`, answered with `status` (or
+    /// "error" without a response) after `latency`.
+    pub fn record_http(&self, endpoint: &str, status: &str, latency: Duration) {
+        self.http
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_owned(), status.to_owned()))
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Record a lookup of the blob cache.
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record `count` blobs evicted from the blob cache.
+    pub fn record_evictions(&self, count: u64) {
+        self.cache_evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Record `bytes` received from the Mega server.
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record `bytes` of file content served to the kernel.
+    pub fn record_read(&self, bytes: usize) {
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// All metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP megafuse_fuse_op_duration_seconds Latency of FUSE operations\n");
+        out.push_str("# TYPE megafuse_fuse_op_duration_seconds histogram\n");
+        for (op, histogram) in self.ops.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "megafuse_fuse_op_duration_seconds",
+                &format!("op=\"{}\"", op),
+            );
+        }
+        out.push_str(
+            "# HELP megafuse_http_request_duration_seconds Latency of requests to the Mega server\n",
+        );
+        out.push_str("# TYPE megafuse_http_request_duration_seconds histogram\n");
+        for ((endpoint, status), histogram) in self.http.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "megafuse_http_request_duration_seconds",
+                &format!("endpoint=\"{}\",status=\"{}\"", endpoint, status),
+            );
+        }
+        for (name, help, counter) in self.counters() {
+            let _ = writeln!(out, "# HELP megafuse_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE megafuse_{} counter", name);
+            let _ = writeln!(out, "megafuse_{} {}", name, counter);
+        }
+        out
+    }
+
+    /// Human readable summary: operation counts and latencies, then the
+    /// counters.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for (op, histogram) in self.ops.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{:<12} {:>8} ops, mean {:.3}ms, p99 {}",
+                op,
+                histogram.count,
+                histogram.sum * 1000.0 / histogram.count as f64,
+                match histogram.quantile(0.99) {
+                    Some(bound) => format!("<= {}ms", bound * 1000.0),
+                    None => format!(
+                        "> {}ms",
+                        LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1] * 1000.0
+                    ),
+                }
+            );
+        }
+        for ((endpoint, status), histogram) in self.http.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{} [{}] {} requests, mean {:.3}ms",
+                endpoint,
+                status,
+                histogram.count,
+                histogram.sum * 1000.0 / histogram.count as f64,
+            );
+        }
+        for (name, _, counter) in self.counters() {
+            let _ = writeln!(out, "{}: {}", name, counter);
+        }
+        out
+    }
+
+    fn counters(&self) -> [(&'static str, &'static str, u64); 5] {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        [
+            (
+                "cache_hits_total",
+                "Blobs served from the cache",
+                load(&self.cache_hits),
+            ),
+            (
+                "cache_misses_total",
+                "Blobs missing from the cache",
+                load(&self.cache_misses),
+            ),
+            (
+                "cache_evictions_total",
+                "Blobs evicted from the cache",
+                load(&self.cache_evictions),
+            ),
+            (
+                "downloaded_bytes_total",
+                "Bytes received from the Mega server",
+                load(&self.downloaded_bytes),
+            ),
+            (
+                "read_bytes_total",
+                "Bytes of file content served",
+                load(&self.read_bytes),
+            ),
+        ]
+    }
+}
+
+/// Serve `global()` metrics over HTTP on `addr` from a background thread,
+/// whatever the requested path.
+pub fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(err) = stream.and_then(respond) {
+                    warn!("Failed to serve metrics: {}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // Skip the request up to the blank line ending its headers
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+    let body = global().render();
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        for millis in [1, 1, 2, 40] {
+            histogram.observe(Duration::from_millis(millis));
+        }
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.quantile(0.5), Some(0.001));
+        assert_eq!(histogram.quantile(0.99), Some(0.1));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "op=\"read\"");
+        assert!(out.contains("latency_bucket{op=\"read\",le=\"0.001\"} 2\n"));
+        assert!(out.contains("latency_bucket{op=\"read\",le=\"+Inf\"} 4\n"));
+        assert!(out.contains("latency_count{op=\"read\"} 4\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_op("read", Duration::from_millis(3));
+        metrics.record_http("/api/v1/object", "200", Duration::from_millis(30));
+        metrics.record_cache_lookup(true);
+        metrics.record_download(1024);
+
+        let out = metrics.render();
+        assert!(out.contains("megafuse_fuse_op_duration_seconds_count{op=\"read\"} 1\n"));
+        assert!(out.contains(
+            "megafuse_http_request_duration_seconds_count{endpoint=\"/api/v1/object\",status=\"200\"} 1\n"
+        ));
+        assert!(out.contains("megafuse_cache_hits_total 1\n"));
+        assert!(out.contains("megafuse_downloaded_bytes_total 1024\n"));
+        assert!(metrics.summary().contains("read"));
+    }
+}
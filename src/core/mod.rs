@@ -1,151 +1,686 @@
+mod access_log;
+/// Typed requests and responses of the Mega API
+pub mod api;
+/// On-disk blob cache and its maintenance
+pub mod cache;
+mod git_view;
+mod handle;
 mod inode;
 /// MegaClient used to dial and communicate with remote mega server
 pub mod mega_client;
+/// Metrics of the mount and their Prometheus exporter
+pub mod metrics;
+mod prefetch;
 mod request;
+mod snapshot;
+/// Sparse checkout profiles restricting the mounted tree
+pub mod sparse;
+mod transport;
+
+pub use inode::{ContentType, InodeAttributes, Object, Objects};
+pub use mega_client::Interrupted;
+pub use transport::Timeout;
 
 use std::{
-    borrow::BorrowMut,
     collections::{HashMap, LinkedList},
-    fs::File,
-    mem::replace,
-    os::fd::AsRawFd,
-    sync::{
-        atomic::{AtomicU32, AtomicUsize, Ordering},
-        Mutex,
-    },
-    time::{Duration, UNIX_EPOCH},
+    io::Read,
+    iter,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
 };
 
-use clap::error;
-use fuser::{consts::FOPEN_DIRECT_IO, FileAttr, FileType, FUSE_ROOT_ID};
-use libc::{ENOENT, ENONET};
-use tokio::io::join;
-use tracing::{debug, error, info};
+use fuser::{
+    consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE},
+    FileType, Notifier, FUSE_ROOT_ID,
+};
+use hyper::StatusCode;
+use libc::{EBADF, EINTR, EIO, EISDIR, ENOENT, ENOTDIR, EROFS, ETIMEDOUT, W_OK};
+use tracing::{debug, error, info, trace, warn};
 
-use crate::core::{
-    inode::{ContentType, Inode, InodeAttributes},
-    mega_client::MegaClient,
+use crate::{
+    config::{Ownership, PrefetchSettings, ValidatedConfig},
+    core::{
+        access_log::AccessLog,
+        cache::{object_id, BlobCache},
+        git_view::{object_kind, tree_content, GitView, GIT_DIR},
+        handle::FileHandle,
+        inode::{Inode, BLOCK_SIZE},
+        mega_client::MegaClient,
+        prefetch::Prefetcher,
+        snapshot::{SubtreeIndex, TreeSnapshot},
+        sparse::{SparseProfile, Visibility},
+        transport::HttpStatus,
+    },
 };
 
-const TTL: Duration = Duration::from_secs(1); // 1 second
 const MAX_NAME_LENGTH: u32 = 255;
+/// Directories listed per batch when walking the remote tree
+const TREE_BATCH_SIZE: usize = 64;
+/// Levels of subdirectories listed along with each batch
+const TREE_BATCH_DEPTH: u32 = 4;
+
+/// Enter the span of the FUSE request `$op` and time it until the end of the
+/// scope. The HTTP requests and cache accesses it causes are child spans.
+macro_rules! fuse_op {
+    ($op:literal, $req:expr, $ino:expr) => {
+        (
+            tracing::debug_span!($op, ino = $ino, pid = $req.pid(), uid = $req.uid()).entered(),
+            metrics::time_op($op),
+        )
+    };
+}
+
+/// Error number reported for a failure to serve a FUSE request: the caller
+/// interrupted or the Mega server too slow are told so, anything else is an
+/// I/O error.
+fn errno(err: &anyhow::Error) -> libc::c_int {
+    if err.is::<Interrupted>() {
+        EINTR
+    } else if err.is::<Timeout>() {
+        ETIMEDOUT
+    } else {
+        EIO
+    }
+}
+
+/// Groups of the caller of `req`: the group FUSE reports, then the
+/// supplementary groups of the process, unknown once it exited.
+fn caller_groups(req: &fuser::Request<'_>) -> Vec<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", req.pid())).unwrap_or_default();
+    let supplementary = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|gid| gid.parse().ok());
+    iter::once(req.gid()).chain(supplementary).collect()
+}
+
+/// Entries of a directory captured at `opendir`, served by `readdir` until the
+/// matching `releasedir`, so a listing stays consistent across several calls.
+type DirSnapshot = Vec<(u64, FileType, String)>;
 
 /// Actually FUSE implementation
 pub struct MegaFUSE {
     target_repo: String,
-    mega_client: MegaClient,
+    /// `None` when mounted offline
+    mega_client: Option<MegaClient>,
+    cache_dir: PathBuf,
+    /// Commit the mounted tree is at, once known
+    commit_id: Option<String>,
     guard: Mutex<()>,
     inodes: HashMap<u64, Inode>,
+    blob_cache: Arc<BlobCache>,
+    prefetcher: Option<Prefetcher>,
+    prefetch: PrefetchSettings,
+    sparse: SparseProfile,
+    file_handles: HashMap<u64, FileHandle>,
+    dir_handles: HashMap<u64, DirSnapshot>,
+    next_fh: u64,
+    direct_io: bool,
+    /// Whether to expose the `.git` view, built once the commit is known
+    git_view: bool,
+    git: Option<GitView>,
+    attr_ttl: Duration,
+    entry_ttl: Duration,
+    negative_ttl: Duration,
+    ownership: Ownership,
+    access_log: Option<AccessLog>,
+    /// Notifier of the session serving this file system, once mounted
+    kernel: Arc<OnceLock<Notifier>>,
 }
 
 impl MegaFUSE {
-    /// Construct MegaFUSE using specified target repo and pre constructed
-    /// MegaClient
-    pub fn from(target_repo: String, mega_client: MegaClient) -> MegaFUSE {
+    /// Construct MegaFUSE using specified target repo, pre constructed
+    /// MegaClient and the validated configuration. Without a MegaClient the
+    /// last known tree is mounted offline, serving cached blobs only.
+    pub fn from(
+        target_repo: String,
+        mega_client: Option<MegaClient>,
+        config: &ValidatedConfig,
+    ) -> MegaFUSE {
+        let blob_cache = Arc::new(BlobCache::new(&config.cache_dir));
+        let prefetcher = match (&mega_client, config.prefetch.concurrency) {
+            (None, _) | (_, 0) => None,
+            (Some(mega_client), concurrency) => Some(Prefetcher::new(
+                mega_client,
+                blob_cache.clone(),
+                concurrency,
+            )),
+        };
+        let access_log = match (config.access_log, &config.log_dir) {
+            (false, _) | (_, None) => None,
+            (true, Some(log_dir)) => match AccessLog::open(log_dir) {
+                Ok(access_log) => Some(access_log),
+                Err(err) => {
+                    warn!("Access log disabled, cannot open it: {}", err);
+                    None
+                }
+            },
+        };
         MegaFUSE {
             target_repo,
             mega_client,
+            cache_dir: config.cache_dir.clone(),
+            commit_id: None,
             guard: Mutex::new(()),
             inodes: HashMap::<u64, Inode>::new(),
+            blob_cache,
+            prefetcher,
+            prefetch: config.prefetch,
+            sparse: config.sparse.clone(),
+            file_handles: HashMap::new(),
+            dir_handles: HashMap::new(),
+            next_fh: 1,
+            direct_io: config.direct_io,
+            git_view: config.git_view,
+            git: None,
+            attr_ttl: config.attr_timeout,
+            entry_ttl: config.entry_timeout,
+            negative_ttl: config.negative_timeout,
+            ownership: config.ownership,
+            access_log,
+            kernel: Arc::default(),
         }
     }
 
-    /// lookup utility
-    pub fn lookup_name(&self, parent: u64, name: &str) -> Option<u64> {
-        let parent_inode = self.inodes.get(&parent).unwrap();
-        for ino in parent_inode.children_ino.iter() {
-            let inode = self.inodes.get(ino).unwrap();
-            if inode.attr.name.eq(name) {
-                return Some(*ino);
-            }
+    /// Slot for the notifier of the session about to serve this file system,
+    /// to invalidate attributes the kernel cached before sizes were known.
+    pub fn notifier(&self) -> Arc<OnceLock<Notifier>> {
+        self.kernel.clone()
+    }
+
+    /// Learn the size of the file `ino` from its cached blob, if any, so it is
+    /// reported right before the file is ever opened.
+    fn refresh_size(&mut self, ino: u64) {
+        let Some(inode) = self.inodes.get_mut(&ino) else {
+            return;
+        };
+        if inode.attr.size_known {
+            return;
+        }
+        if let Some(metadata) = self
+            .blob_cache
+            .open(&inode.attr.id)
+            .and_then(|file| file.metadata().ok())
+        {
+            inode.attr.size = metadata.len();
+            inode.attr.size_known = true;
         }
-        None
     }
-}
 
-impl fuser::Filesystem for MegaFUSE {
-    fn init(
+    fn alloc_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    /// The inode `ino`, of the mounted tree or of the `.git` view.
+    fn inode(&self, ino: u64) -> Option<&Inode> {
+        self.inodes
+            .get(&ino)
+            .or_else(|| self.git.as_ref()?.get(ino))
+    }
+
+    /// Path of inode `ino` relative to the repository root.
+    fn inode_path(&self, ino: u64) -> PathBuf {
+        let mut names = Vec::new();
+        let mut inode = self.inodes.get(&ino);
+        while let Some(current) = inode.filter(|inode| inode.ino != FUSE_ROOT_ID) {
+            names.push(current.attr.name.as_str());
+            inode = self.inodes.get(&current.parent_ino);
+        }
+        names.iter().rev().collect()
+    }
+
+    /// Summarize the inode table as `(files, bytes, cached_bytes)`: the number
+    /// of inodes, the total size of known files and the size of the blob
+    /// cache.
+    pub fn usage(&self) -> (u64, u64, u64) {
+        let (files, bytes) = self
+            .inodes
+            .values()
+            .fold((0, 0), |(files, bytes), inode| match inode.attr.kind {
+                ContentType::File => (files + 1, bytes + inode.attr.size),
+                ContentType::Dir => (files + 1, bytes),
+            });
+        (files, bytes, self.blob_cache.usage())
+    }
+
+    /// Queue the blobs of the files in directory `dir` for prefetching, up to
+    /// the configured limit.
+    fn prefetch_dir(&self, dir: u64) {
+        let Some(prefetcher) = &self.prefetcher else {
+            return;
+        };
+        let Some(dir) = self.inodes.get(&dir) else {
+            return;
+        };
+        let ids = dir
+            .children_ino
+            .iter()
+            .filter_map(|ino| self.inodes.get(ino))
+            .filter(|inode| inode.attr.kind == ContentType::File)
+            .filter(|inode| !self.blob_cache.contains(&inode.attr.id))
+            .map(|inode| inode.attr.id.clone())
+            .take(self.prefetch.limit);
+        prefetcher.prefetch(ids);
+    }
+
+    /// Open the blob with object id `id` from the blob cache, downloading it
+    /// first for process `pid` if it is not cached yet.
+    fn fetch_blob(&mut self, id: &str, pid: u32) -> anyhow::Result<std::fs::File> {
+        if let Some(file) = self.blob_cache.get(id) {
+            return Ok(file);
+        }
+        let Some(mega_client) = self.mega_client.as_mut() else {
+            anyhow::bail!("blob {} is not cached and the mount is offline", id);
+        };
+        // Another mount or a prefetch worker may be downloading the same blob
+        let _lock = self.blob_cache.lock(id)?;
+        if let Some(file) = self.blob_cache.open(id) {
+            return Ok(file);
+        }
+        mega_client.set_caller(Some(pid));
+        let content = mega_client.request_file_content(id);
+        mega_client.set_caller(None);
+        Ok(self.blob_cache.insert(id, &content?)?)
+    }
+
+    /// Content and type of the git object `id`: a tree of the mounted tree
+    /// if it can be rebuilt as is, a cached blob, or else whatever the Mega
+    /// server returns for it, requested for process `pid`.
+    fn git_object(&mut self, id: &str, pid: u32) -> anyhow::Result<(&'static str, Vec<u8>)> {
+        if let Some(content) = self.tree_object(id) {
+            return Ok(("tree", content));
+        }
+        if let Some(mut file) = self.blob_cache.open(id) {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            return Ok(("blob", content));
+        }
+        let Some(mega_client) = self.mega_client.as_mut() else {
+            anyhow::bail!("object {} is not cached and the mount is offline", id);
+        };
+        mega_client.set_caller(Some(pid));
+        let content = mega_client.request_object(&self.target_repo, id);
+        mega_client.set_caller(None);
+        let content = content?;
+        let Some(kind) = object_kind(id, &content) else {
+            anyhow::bail!("object {} does not match its content", id);
+        };
+        if kind == "blob" {
+            self.blob_cache.insert(id, &content)?;
+        }
+        Ok((kind, content.to_vec()))
+    }
+
+    /// Tree object of the mounted directory with object id `id`, unless its
+    /// entries do not hash back to `id`: left out by the sparse profile, or
+    /// with modes the tree API does not tell.
+    fn tree_object(&self, id: &str) -> Option<Vec<u8>> {
+        let dir = self.inodes.get(&self.git.as_ref()?.tree(id)?)?;
+        let entries = dir
+            .children_ino
+            .iter()
+            .filter_map(|ino| self.inodes.get(ino))
+            .map(|inode| &inode.attr);
+        tree_content(entries).filter(|content| object_id("tree", content) == id)
+    }
+
+    /// Look `name` up in the directory `parent` of the `.git` view, fetching
+    /// the object it names on first use for process `pid`. Objects the server
+    /// does not have, or that cannot be fetched offline, are missing; other
+    /// failures are returned as an errno.
+    fn git_lookup(
         &mut self,
-        _req: &fuser::Request<'_>,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        // Retrieve the basic `Tree` from the specified remote repository,
-        // recursively initialize the directory
+        parent: u64,
+        name: &str,
+        pid: u32,
+    ) -> Result<Option<u64>, libc::c_int> {
+        let Some(git) = self.git.as_mut() else {
+            return Ok(None);
+        };
+        if let Some(ino) = git.lookup_name(parent, name) {
+            return Ok(Some(ino));
+        }
+        let Some(id) = git.object_id(parent, name) else {
+            return Ok(None);
+        };
+        let inserted = self.git_object(&id, pid).and_then(|(kind, content)| {
+            let git = self.git.as_mut().unwrap();
+            Ok(git.insert_object(parent, &id, kind, &content)?)
+        });
+        match inserted {
+            Ok(ino) => Ok(Some(ino)),
+            Err(err) => {
+                warn!("git object {} unavailable: {:?}", id, err);
+                // Only an object known to be missing may be cached as such, a
+                // failed request is retried on the next lookup
+                let missing = self.mega_client.is_none()
+                    || err
+                        .downcast_ref::<HttpStatus>()
+                        .is_some_and(|err| err.status == StatusCode::NOT_FOUND);
+                match missing {
+                    true => Ok(None),
+                    false => Err(errno(&err)),
+                }
+            }
+        }
+    }
+
+    /// Build the inode table by walking the tree of the remote repository at
+    /// its head commit. A snapshot of the same commit is restored as is,
+    /// otherwise unchanged subtrees are taken from the latest snapshot instead
+    /// of being requested.
+    fn load_remote_tree(&mut self) -> anyhow::Result<()> {
+        let guard = self.guard.lock().unwrap();
+        let mega_client = self.mega_client.as_mut().unwrap();
+        let commit = mega_client.request_head_commit(&self.target_repo)?;
         info!(
-            "Initialize filesystem of target {} repository",
-            &self.target_repo
+            "{} is at commit {}: {}",
+            &self.target_repo, commit.oid, commit.short_message
         );
-        let guard = self.guard.lock().unwrap();
+        let commit_id = self.commit_id.insert(commit.oid);
+
+        let snapshot = TreeSnapshot::load(&self.cache_dir, &self.target_repo, Some(commit_id));
+        // A snapshot loaded with another sparse profile may miss entries
+        if let Some(snapshot) = snapshot.ok().filter(|s| s.sparse() == &self.sparse) {
+            info!(
+                "Tree of {} at {} restored from snapshot",
+                &self.target_repo, commit_id
+            );
+            self.inodes = snapshot.restore(&self.sparse);
+            return Ok(());
+        }
+        // First time through, constructing basic tree
+        let top_level: Vec<InodeAttributes> = mega_client
+            .request_base_tree(&self.target_repo)?
+            .data
+            .into_iter()
+            .map(InodeAttributes::from)
+            .collect();
+        // Directories of a snapshot loaded with another sparse profile may miss
+        // children, unless nothing was left out
+        let index = TreeSnapshot::load(&self.cache_dir, &self.target_repo, None)
+            .ok()
+            .filter(|s| s.sparse() == &self.sparse || s.sparse().is_full())
+            .map(|snapshot| SubtreeIndex::new(&snapshot))
+            .unwrap_or_default();
+
         self.inodes
             .insert(FUSE_ROOT_ID, Inode::root_node(&self.target_repo));
-        // Used to iterate the objects level by level (request by request)
-        let mut queue = LinkedList::from([FUSE_ROOT_ID]);
-        while let Some(ino) = queue.pop_front() {
-            let inode = self.inodes.get_mut(&ino).unwrap();
-            let path = &inode.attr.path;
-
-            let objects = match ino {
-                // First time through, constructing basic tree
-                FUSE_ROOT_ID => self.mega_client.request_base_tree(&self.target_repo),
-                _ => {
-                    // Request sub-directories
-                    self.mega_client
-                        .request_sub_tree_with_id(&self.target_repo, &inode.attr.id)
+        // Used to iterate the objects level by level, along with their path
+        // relative to the repository root
+        let mut queue = LinkedList::from([(FUSE_ROOT_ID, PathBuf::new())]);
+        let mut top_level = Some(top_level);
+        // Directories listed ahead of time by batched requests
+        let mut listed: HashMap<String, Vec<InodeAttributes>> = HashMap::new();
+        // Queued directories per object id, identical subtrees sharing theirs
+        let mut queued: HashMap<String, usize> = HashMap::new();
+        let mut reused = 0;
+        while let Some((ino, path)) = queue.pop_front() {
+            let id = self.inodes[&ino].attr.id.clone();
+            if let Some(count) = queued.get_mut(&id) {
+                *count -= 1;
+                if *count == 0 {
+                    queued.remove(&id);
+                }
+            }
+            let children = match (ino, index.children(&id)) {
+                (FUSE_ROOT_ID, _) => top_level.take().unwrap(),
+                (_, Some(children)) => {
+                    reused += 1;
+                    children.to_vec()
+                }
+                // Request sub-directories, along with the directories queued
+                // after this one and their subtrees
+                (_, None) => {
+                    if !listed.contains_key(&id) {
+                        let dirs: Vec<(String, PathBuf)> = iter::once((&id, &path))
+                            .chain(
+                                queue
+                                    .iter()
+                                    .map(|(ino, path)| (&self.inodes[ino].attr.id, path)),
+                            )
+                            .filter(|(id, _)| !listed.contains_key(*id))
+                            .filter(|(id, _)| index.children(id).is_none())
+                            .take(TREE_BATCH_SIZE)
+                            .map(|(id, path)| (id.clone(), path.clone()))
+                            .collect();
+                        let sparse = &self.sparse;
+                        let trees = mega_client.request_sub_trees(
+                            &self.target_repo,
+                            &dirs,
+                            TREE_BATCH_DEPTH,
+                            |path| sparse.visibility(path, true) == Visibility::Visible,
+                        )?;
+                        listed.extend(trees.into_iter().map(|(id, objects)| {
+                            (id, objects.into_iter().map(InodeAttributes::from).collect())
+                        }));
+                    }
+                    // Kept for the identical subtrees still queued
+                    if queued.contains_key(&id) {
+                        listed.get(&id).cloned().unwrap_or_default()
+                    } else {
+                        listed.remove(&id).unwrap_or_default()
+                    }
                 }
             };
-            let new_inodes: Vec<Inode> = objects
-                .data
+            let inode = self.inodes.get_mut(&ino).unwrap();
+            let new_inodes: Vec<Inode> = children
                 .into_iter()
-                .map(|object| {
-                    // retrieve attributes from object
-                    let attr = InodeAttributes::from(object);
+                .filter_map(|attr| {
                     let kind = attr.kind.clone();
+                    let child_path = path.join(&attr.name);
+                    let visibility = self
+                        .sparse
+                        .visibility(&child_path, kind == ContentType::Dir);
+                    if visibility == Visibility::Hidden {
+                        return None;
+                    }
                     let new_inode = Inode::new(ino, attr);
-                    if kind == ContentType::Dir {
-                        queue.push_back(new_inode.ino);
+                    // Stubs are exposed, but their content is never retrieved
+                    if kind == ContentType::Dir && visibility == Visibility::Visible {
+                        *queued.entry(new_inode.attr.id.clone()).or_default() += 1;
+                        queue.push_back((new_inode.ino, child_path));
                     }
                     inode.insert_child(new_inode.ino);
-                    new_inode
+                    Some(new_inode)
                 })
                 .collect();
             new_inodes.into_iter().for_each(|inode| {
-                info!("Constructing {:?}", inode);
+                trace!("Constructing {:?}", inode);
                 self.inodes.insert(inode.ino, inode);
             });
         }
+        info!(
+            "{} unchanged directories reused from the last snapshot",
+            reused
+        );
         drop(guard);
+        Ok(())
+    }
+
+    /// Persist the current tree, so it can be mounted offline later on.
+    fn save_snapshot(&self) {
+        let Some(commit_id) = &self.commit_id else {
+            return;
+        };
+        if self.inodes.is_empty() {
+            return;
+        }
+        let snapshot =
+            TreeSnapshot::capture(&self.target_repo, commit_id, &self.sparse, &self.inodes);
+        match snapshot.save(&self.cache_dir) {
+            Ok(()) => debug!(
+                "Tree snapshot of {} at {} saved",
+                &self.target_repo, commit_id
+            ),
+            Err(err) => warn!(
+                "Failed to save tree snapshot of {}: {}",
+                &self.target_repo, err
+            ),
+        }
+    }
+
+    /// Load the tree of the repository, as done when mounted: from the Mega
+    /// server, or from the last snapshot when offline. Embedders call it
+    /// before resolving paths.
+    pub fn load(&mut self) -> Result<(), libc::c_int> {
+        // Retrieve the basic `Tree` from the specified remote repository,
+        // recursively initialize the directory
+        info!(
+            "Initialize filesystem of target {} repository",
+            &self.target_repo
+        );
+        if self.mega_client.is_some() {
+            if let Err(err) = self.load_remote_tree() {
+                error!(
+                    "Failed to load the tree of {}: {:?}",
+                    &self.target_repo, err
+                );
+                return Err(errno(&err));
+            }
+            self.save_snapshot();
+        } else {
+            info!(
+                "Offline: mounting the last known tree of {}",
+                &self.target_repo
+            );
+            match TreeSnapshot::load(&self.cache_dir, &self.target_repo, None) {
+                Ok(snapshot) => {
+                    self.commit_id = Some(snapshot.commit_id().to_owned());
+                    self.inodes = snapshot.restore(&self.sparse);
+                }
+                Err(err) => {
+                    error!(
+                        "No usable tree snapshot of {} in {:?}: {}",
+                        &self.target_repo, &self.cache_dir, err
+                    );
+                    return Err(EIO);
+                }
+            }
+        }
+        if self.git_view {
+            match &self.commit_id {
+                Some(commit_id) => {
+                    let refs = match self.mega_client.as_mut() {
+                        Some(mega_client) => mega_client
+                            .request_refs(&self.target_repo)
+                            .unwrap_or_else(|err| {
+                                warn!("Branches and tags of the .git view unknown: {:#}", err);
+                                Vec::new()
+                            }),
+                        None => Vec::new(),
+                    };
+                    let git = GitView::new(
+                        &self.cache_dir,
+                        &self.target_repo,
+                        commit_id,
+                        &refs,
+                        &self.inodes,
+                    );
+                    match git {
+                        Ok(git) => self.git = Some(git),
+                        Err(err) => warn!("Failed to set up the .git view: {}", err),
+                    }
+                }
+                None => warn!("No .git view: the commit of the tree is unknown"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve `path`, relative to the repository root, to its inode number
+    /// and attributes. Paths outside of the sparse profile do not resolve.
+    pub fn resolve_path(&self, path: &Path) -> Option<(u64, &InodeAttributes)> {
+        self.inodes.get(&FUSE_ROOT_ID)?;
+        let mut ino = FUSE_ROOT_ID;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => ino = self.lookup_name(ino, name.to_str()?)?,
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        self.inodes.get(&ino).map(|inode| (ino, &inode.attr))
+    }
+
+    /// lookup utility
+    pub fn lookup_name(&self, parent: u64, name: &str) -> Option<u64> {
+        let parent_inode = self.inodes.get(&parent).unwrap();
+        for ino in parent_inode.children_ino.iter() {
+            let inode = self.inodes.get(ino).unwrap();
+            if inode.attr.name.eq(name) {
+                return Some(*ino);
+            }
+        }
+        None
+    }
+}
+
+impl fuser::Filesystem for MegaFUSE {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        _config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        let _op = fuse_op!("init", req, FUSE_ROOT_ID);
+        self.load()?;
         info!("File system init success.");
-        // Request repo with `repo_name` specified to get the basic layout
         Ok(())
     }
 
+    fn destroy(&mut self) {
+        // Sizes of the files opened during this session are worth keeping
+        self.save_snapshot();
+        if let Err(err) = self.blob_cache.flush_stats() {
+            warn!("Failed to save blob cache statistics: {}", err);
+        }
+        info!("Metrics of this mount:\n{}", metrics::global().summary());
+    }
+
     fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        match self.inodes.get(&ino) {
+        let _op = fuse_op!("getattr", req, ino);
+        self.refresh_size(ino);
+        match self.inode(ino) {
             Some(inode) => {
                 debug!("getattr(file at inode: {})", ino);
-                reply.attr(&TTL, &inode.file_attr(req.uid(), req.gid()));
+                reply.attr(&self.attr_ttl, &inode.file_attr(&self.ownership));
             }
             None => reply.error(ENOENT),
         }
     }
 
-    fn readdir(
+    fn opendir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: fuser::ReplyDirectory,
+        _flags: i32,
+        reply: fuser::ReplyOpen,
     ) {
-        let inode = self.inodes.get(&ino).unwrap();
+        let _op = fuse_op!("opendir", req, ino);
+        let inode = match self.inode(ino) {
+            Some(inode) => inode,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        if inode.attr.kind != ContentType::Dir {
+            reply.error(ENOTDIR);
+            return;
+        }
         let mut entries = vec![
             (ino, FileType::Directory, ".".to_owned()),
-            (ino, FileType::Directory, "..".to_owned()),
+            (inode.parent_ino, FileType::Directory, "..".to_owned()),
         ];
-        let children: Vec<(u64, FileType, String)> = inode
+        let children: DirSnapshot = inode
             .children_ino
             .iter()
-            .map(|ino| self.inodes.get(ino).unwrap())
+            .map(|ino| self.inode(*ino).unwrap())
             .map(|inode| {
                 (
                     inode.ino,
@@ -155,15 +690,102 @@ impl fuser::Filesystem for MegaFUSE {
             })
             .collect();
         entries.extend(children);
+        if let Some(git) = self.git.as_ref().filter(|_| ino == FUSE_ROOT_ID) {
+            entries.push((git.root(), FileType::Directory, GIT_DIR.to_owned()));
+        }
+
+        if self.prefetch.trigger.on_readdir() {
+            self.prefetch_dir(ino);
+        }
+
+        let fh = self.alloc_fh();
+        debug!("opendir(inode: {}) -> fh: {}", ino, fh);
+        self.dir_handles.insert(fh, entries);
+        reply.opened(fh, 0);
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        let _op = fuse_op!("readdir", req, ino);
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                error!("readdir(inode: {}) with unknown fh: {}", ino, fh);
+                reply.error(EBADF);
+                return;
+            }
+        };
 
-        for (index, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(ino, (index + 1) as i64, kind, name) {
+        for (index, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, (index + 1) as i64, *kind, name) {
                 break;
             }
         }
         reply.ok();
     }
 
+    fn releasedir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _op = fuse_op!("releasedir", req, ino);
+        debug!("releasedir(inode: {}, fh: {})", ino, fh);
+        match self.dir_handles.remove(&fh) {
+            Some(_) => reply.ok(),
+            None => reply.error(EBADF),
+        }
+    }
+
+    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        let _op = fuse_op!("statfs", req, ino);
+        let (files, bytes, cached) = self.usage();
+        debug!(
+            "statfs: {} inodes, {} bytes known, {} bytes cached",
+            files, bytes, cached
+        );
+        // The mount is read-only, so there is neither free space nor free inodes
+        let blocks = bytes.max(cached).div_ceil(BLOCK_SIZE as u64);
+        reply.statfs(
+            blocks,
+            0,
+            0,
+            files,
+            0,
+            BLOCK_SIZE,
+            MAX_NAME_LENGTH,
+            BLOCK_SIZE,
+        );
+    }
+
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let _op = fuse_op!("access", req, ino);
+        let inode = match self.inode(ino) {
+            Some(inode) => inode,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        debug!("access(inode: {}, mask: {:o})", ino, mask);
+        if mask & W_OK != 0 {
+            reply.error(EROFS);
+        } else if inode.permits(&self.ownership, req.uid(), &caller_groups(req), mask) {
+            reply.ok();
+        } else {
+            reply.error(libc::EACCES);
+        }
+    }
+
     fn lookup(
         &mut self,
         req: &fuser::Request<'_>,
@@ -171,86 +793,265 @@ impl fuser::Filesystem for MegaFUSE {
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        let _op = fuse_op!("lookup", req, parent);
         if name.len() > MAX_NAME_LENGTH as usize {
             reply.error(libc::ENAMETOOLONG);
             return;
         }
         let name = name.to_str().unwrap().to_owned();
         debug!("lookup({} at inode)", name);
-        match self.lookup_name(parent, &name) {
+        let found = match &self.git {
+            Some(git) if parent == FUSE_ROOT_ID && name == GIT_DIR => Some(git.root()),
+            Some(git) if git.get(parent).is_some() => {
+                match self.git_lookup(parent, &name, req.pid()) {
+                    Ok(found) => found,
+                    // Not cached as missing
+                    Err(errno) => {
+                        reply.error(errno);
+                        return;
+                    }
+                }
+            }
+            _ => self.lookup_name(parent, &name),
+        };
+        match found {
             Some(ino) => {
-                let inode = self.inodes.get(&ino).unwrap();
-                reply.entry(&TTL, &inode.file_attr(req.uid(), req.gid()), 0)
+                self.refresh_size(ino);
+                let inode = self.inode(ino).unwrap();
+                reply.entry(&self.entry_ttl, &inode.file_attr(&self.ownership), 0)
             }
-            None => reply.error(libc::ENOENT),
+            None if !self.negative_ttl.is_zero() => match self.inode(parent) {
+                // An entry with inode number 0 makes the kernel cache the miss
+                // for `negative_ttl` instead of asking again
+                Some(parent) => {
+                    let mut attr = parent.file_attr(&self.ownership);
+                    attr.ino = 0;
+                    reply.entry(&self.negative_ttl, &attr, 0)
+                }
+                // A stale parent, after the tree was reloaded
+                None => reply.error(ENOENT),
+            },
+            None => reply.error(ENOENT),
         }
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        let _op = fuse_op!("open", req, ino);
+        // Files of the `.git` view never change once written
+        if let Some(opened) = self.git.as_ref().and_then(|git| git.open(ino)) {
+            match opened {
+                Ok(handle) => {
+                    let fh = self.alloc_fh();
+                    self.file_handles.insert(fh, handle);
+                    reply.opened(fh, FOPEN_KEEP_CACHE);
+                }
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            }
+            return;
+        }
+        let (id, name, parent) = match self.inodes.get(&ino) {
+            Some(inode) if inode.attr.kind == ContentType::Dir => {
+                reply.error(EISDIR);
+                return;
+            }
+            Some(inode) => (
+                inode.attr.id.clone(),
+                inode.attr.name.clone(),
+                inode.parent_ino,
+            ),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        debug!("open({})", name);
+        // Queue the siblings first so they download while this one does
+        if self.prefetch.trigger.on_open() {
+            self.prefetch_dir(parent);
+        }
+
+        let handle = match self
+            .fetch_blob(&id, req.pid())
+            .and_then(|file| Ok(FileHandle::new(ino, id, file)?))
+        {
+            Ok(handle) => handle,
+            Err(err) => {
+                error!("failed to open {} at inode: {}: {:?}", name, ino, err);
+                reply.error(errno(&err));
+                return;
+            }
+        };
         let inode = self.inodes.get_mut(&ino).unwrap();
-        debug!("open({})", inode.attr.name);
-        // Instantiate the file at inode `ino`
+        let size_reported = inode.attr.size_known;
+        inode.attr.size = handle.size;
+        inode.attr.size_known = true;
+        if !size_reported {
+            // The kernel may have cached a size of 0: have it ask again before
+            // the open completes. Attributes only, pages are left alone.
+            let invalidated = self
+                .kernel
+                .get()
+                .map(|kernel| kernel.inval_inode(ino, -1, 0));
+            if let Some(Err(err)) = invalidated {
+                debug!(
+                    "Failed to invalidate the attributes of inode {}: {}",
+                    ino, err
+                );
+            }
+        }
+        // Content is immutable per object id: pages cached by the kernel stay
+        // valid as long as the inode still points to the same object, otherwise
+        // opening without `FOPEN_KEEP_CACHE` makes the kernel drop them
+        let open_flags = if self.direct_io {
+            FOPEN_DIRECT_IO
+        } else if inode.cached_id.as_deref() == Some(handle.id.as_str()) {
+            FOPEN_KEEP_CACHE
+        } else {
+            inode.cached_id = Some(handle.id.clone());
+            0
+        };
 
-        let file_content = self
-            .mega_client
-            .request_file_content(&self.target_repo, &inode.attr.id);
-        inode.attr.size = file_content.len() as u64;
-        inode.content = Some(file_content);
-        debug!("{:?}", inode);
+        if self.access_log.is_some() {
+            let path = self.inode_path(ino);
+            if let Some(access_log) = self.access_log.as_mut() {
+                access_log.record(req.pid(), "open", &path);
+            }
+        }
 
-        reply.opened(ino, FOPEN_DIRECT_IO);
+        let fh = self.alloc_fh();
+        debug!("open({}) -> fh: {}, flags: {:#x}", name, fh, open_flags);
+        self.file_handles.insert(fh, handle);
+        reply.opened(fh, open_flags);
     }
 
     fn flush(
         &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
+        req: &fuser::Request<'_>,
+        ino: u64,
         _fh: u64,
         _lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
+        let _op = fuse_op!("flush", req, ino);
         reply.ok()
     }
 
     fn read(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
         size: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
+        let _op = fuse_op!("read", req, ino);
         assert!(offset >= 0);
-        // if (fh & ino) != 0 {
-        //     reply.error(libc::EACCES);
-        //     return;
-        // }
-        // Instantiate the file at inode `ino`
-        let file_content = match self.inodes.get(&ino) {
-            Some(inode) => {
-                debug!("read({})", inode.attr.name);
-                inode.content.as_ref().unwrap()
-            }
-            None => {
-                reply.error(libc::ENOENT);
-                error!("content at inode: {} not found, aborting", ino);
+        let handle = match self.file_handles.get_mut(&fh) {
+            Some(handle) if handle.ino == ino => handle,
+            _ => {
+                error!("read(inode: {}) with invalid fh: {}", ino, fh);
+                reply.error(EBADF);
                 return;
             }
         };
+        debug!("read(inode: {}, fh: {}, object: {})", ino, fh, handle.id);
+
+        match handle.read(offset as u64, size) {
+            Ok(data) => {
+                metrics::global().record_read(data.len());
+                reply.data(&data)
+            }
+            Err(err) => {
+                error!("read(inode: {}) failed: {}", ino, err);
+                reply.error(EIO);
+            }
+        }
+
+        // Reads come by the thousand, only the first one of a handle is logged
+        if let Some(handle) = self.file_handles.get_mut(&fh) {
+            if self.access_log.is_some() && !handle.logged_read {
+                handle.logged_read = true;
+                let path = self.inode_path(ino);
+                if let Some(access_log) = self.access_log.as_mut() {
+                    access_log.record(req.pid(), "read", &path);
+                }
+            }
+        }
+    }
 
-        reply.data(file_content[offset as usize..].as_bytes());
+    fn release(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _op = fuse_op!("release", req, ino);
+        debug!("release(inode: {}, fh: {})", ino, fh);
+        match self.file_handles.remove(&fh) {
+            Some(_) => reply.ok(),
+            None => reply.error(EBADF),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::core::mega_client::tests::create_sample_config;
+
     // use crate::core::SAMPLE_FILE_CONTENT;
 
     // #[test]
     // fn get_sample_length() {
     //     println!("{}", SAMPLE_FILE_CONTENT.len());
     // }
+
+    fn attr(name: &str, kind: ContentType) -> InodeAttributes {
+        InodeAttributes {
+            id: format!("id-of-{}", name),
+            commit_id: String::new(),
+            size: 0,
+            size_known: true,
+            name: name.to_owned(),
+            kind,
+            path: name.to_owned(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            permissions: 0o644,
+        }
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let mut fs = MegaFUSE::from("fuser".to_owned(), None, &create_sample_config());
+        assert!(fs.resolve_path(Path::new("src")).is_none());
+
+        let mut root = Inode::root_node("fuser");
+        let mut src = Inode::new(FUSE_ROOT_ID, attr("src", ContentType::Dir));
+        let lib = Inode::new(src.ino, attr("lib.rs", ContentType::File));
+        root.insert_child(src.ino);
+        src.insert_child(lib.ino);
+        let (src_ino, lib_ino) = (src.ino, lib.ino);
+        fs.inodes = [root, src, lib]
+            .into_iter()
+            .map(|inode| (inode.ino, inode))
+            .collect();
+
+        let (ino, attr) = fs.resolve_path(Path::new("src/lib.rs")).unwrap();
+        assert_eq!(ino, lib_ino);
+        assert_eq!(attr.id, "id-of-lib.rs");
+        assert_eq!(fs.resolve_path(Path::new("/src/")).unwrap().0, src_ino);
+        assert_eq!(fs.resolve_path(Path::new("")).unwrap().0, FUSE_ROOT_ID);
+        assert!(fs.resolve_path(Path::new("src/main.rs")).is_none());
+        assert!(fs.resolve_path(Path::new("src/../src")).is_none());
+    }
 }
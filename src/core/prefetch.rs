@@ -0,0 +1,127 @@
+//! Background prefetching of blobs into the blob cache. Build systems tend to
+//! open every file of a directory in quick succession, so once a directory is
+//! listed or one of its files opened, the blobs of its siblings are downloaded
+//! ahead of time by a bounded number of workers.
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex as AsyncMutex,
+};
+use tracing::{debug, debug_span, warn, Instrument};
+
+use crate::{
+    config::ApiSettings,
+    core::{
+        api::{BlobRequest, Endpoint},
+        cache::BlobCache,
+        mega_client::{self, MegaClient},
+        transport::Transport,
+    },
+};
+
+/// Ids waiting to be prefetched beyond this are dropped rather than queued.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Prefetches blobs with a fixed number of workers, sharing the connections of
+/// the `MegaClient`: multiplexed with FUSE requests over HTTP/2, or taken from
+/// the pool over HTTP/1, so prefetching never blocks FUSE requests.
+#[derive(Debug)]
+pub struct Prefetcher {
+    queue: mpsc::Sender<String>,
+    cache: Arc<BlobCache>,
+    /// Ids queued or being downloaded, to avoid fetching a blob twice
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Prefetcher {
+    /// Spawn `concurrency` workers onto the runtime of `mega_client`, fetching
+    /// blobs into `cache`.
+    pub fn new(mega_client: &MegaClient, cache: Arc<BlobCache>, concurrency: usize) -> Prefetcher {
+        let (queue, receiver) = mpsc::channel::<String>(QUEUE_CAPACITY);
+        let receiver = Arc::new(AsyncMutex::new(receiver));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+
+        for worker in 0..concurrency {
+            let transport = mega_client.transport().clone();
+            let api = mega_client.api().clone();
+            let receiver = receiver.clone();
+            let cache = cache.clone();
+            let pending = pending.clone();
+            mega_client.runtime().spawn(async move {
+                loop {
+                    let Some(id) = receiver.lock().await.recv().await else {
+                        break;
+                    };
+                    // Skip blobs another mount is already downloading
+                    let lock = match cache.try_lock(&id) {
+                        Ok(lock) => lock,
+                        Err(err) => {
+                            warn!("prefetch worker {} cannot lock {}: {:?}", worker, id, err);
+                            None
+                        }
+                    };
+                    if lock.is_some() && !cache.contains(&id) {
+                        if let Err(err) = fetch(&transport, &api, &id, &cache)
+                            .instrument(debug_span!("prefetch", worker, id))
+                            .await
+                        {
+                            warn!("prefetch worker {} failed on {}: {:?}", worker, id, err);
+                        }
+                    }
+                    drop(lock);
+                    pending.lock().unwrap().remove(&id);
+                }
+            });
+        }
+
+        Prefetcher {
+            queue,
+            cache,
+            pending,
+        }
+    }
+
+    /// Queue the blobs with object ids `ids` for prefetching, skipping those
+    /// already cached or queued.
+    pub fn prefetch<I: IntoIterator<Item = String>>(&self, ids: I) {
+        let mut pending = self.pending.lock().unwrap();
+        for id in ids {
+            if pending.contains(&id) || self.cache.contains(&id) {
+                continue;
+            }
+            match self.queue.try_send(id.clone()) {
+                Ok(()) => {
+                    pending.insert(id);
+                }
+                Err(TrySendError::Full(_)) => {
+                    debug!("prefetch queue full, dropping the remaining blobs");
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => break,
+            }
+        }
+    }
+}
+
+async fn fetch(
+    transport: &Transport,
+    api: &ApiSettings,
+    id: &str,
+    cache: &BlobCache,
+) -> anyhow::Result<()> {
+    let uri = BlobRequest {
+        object_id: id.to_owned(),
+    }
+    .uri(api);
+    let req = MegaClient::form_request_to(&uri);
+    let content = transport.send(req).await?;
+    // Not retried: opening the file downloads it again if need be
+    mega_client::verify_blob(id, &content)?;
+    cache.insert(id, &content)?;
+    debug!("prefetched {} ({} bytes)", id, content.len());
+    Ok(())
+}
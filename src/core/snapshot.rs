@@ -0,0 +1,403 @@
+//! Snapshots of the tree metadata persisted in `cache_dir` after each
+//! successful load. They are keyed by repository and commit id:
+//! - a remount of an unchanged commit restores its snapshot as is, inode
+//!   numbers included,
+//! - otherwise the latest snapshot of the repository serves as an index of
+//!   subtrees by object id, so only changed subtrees are requested again,
+//! - while the Mega server is unreachable, the latest snapshot is mounted.
+//!
+//! Only the `KEPT_SNAPSHOTS` most recent snapshots of a repository are kept.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use fuser::FUSE_ROOT_ID;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    cache::check_object_id,
+    inode::{ContentType, Inode, InodeAttributes},
+    sparse::{SparseProfile, Visibility},
+};
+
+/// Directory of `cache_dir` holding the snapshots of every repository
+pub const TREES_DIR: &str = "trees";
+/// File in the directory of a repository naming its most recent snapshot
+const LATEST: &str = "LATEST";
+/// Snapshots kept per repository, older ones are removed on save
+const KEPT_SNAPSHOTS: usize = 3;
+
+/// One inode of a `TreeSnapshot`
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotNode {
+    ino: u64,
+    parent_ino: u64,
+    attr: InodeAttributes,
+}
+
+/// The inode table of a repository at a commit, nodes are stored parents
+/// first.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TreeSnapshot {
+    repo: String,
+    commit_id: String,
+    /// Profile the tree was loaded with, entries outside of it are missing
+    sparse: SparseProfile,
+    nodes: Vec<SnapshotNode>,
+}
+
+impl TreeSnapshot {
+    /// Capture the inodes reachable from the root of `inodes`, loaded with
+    /// the sparse profile `sparse`.
+    pub fn capture(
+        repo: &str,
+        commit_id: &str,
+        sparse: &SparseProfile,
+        inodes: &HashMap<u64, Inode>,
+    ) -> TreeSnapshot {
+        let mut nodes = Vec::with_capacity(inodes.len());
+        let mut queue = VecDeque::from([FUSE_ROOT_ID]);
+        while let Some(ino) = queue.pop_front() {
+            let Some(inode) = inodes.get(&ino) else {
+                continue;
+            };
+            queue.extend(inode.children_ino.iter().copied());
+            nodes.push(SnapshotNode {
+                ino,
+                parent_ino: inode.parent_ino,
+                attr: inode.attr.clone(),
+            });
+        }
+        TreeSnapshot {
+            repo: repo.to_owned(),
+            commit_id: commit_id.to_owned(),
+            sparse: sparse.clone(),
+            nodes,
+        }
+    }
+
+    /// Commit the snapshot was taken at.
+    pub fn commit_id(&self) -> &str {
+        &self.commit_id
+    }
+
+    /// Repository the snapshot was taken of.
+    pub fn repo(&self) -> &str {
+        &self.repo
+    }
+
+    /// Object ids of the files in the snapshot.
+    pub fn blob_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes
+            .iter()
+            .filter(|node| node.attr.kind == ContentType::File)
+            .map(|node| node.attr.id.as_str())
+    }
+
+    /// Sparse profile the snapshot was loaded with.
+    pub fn sparse(&self) -> &SparseProfile {
+        &self.sparse
+    }
+
+    /// Rebuild the inode table, keeping the inode numbers of the snapshot and
+    /// dropping whatever `sparse` does not expose.
+    pub fn restore(self, sparse: &SparseProfile) -> HashMap<u64, Inode> {
+        let mut inodes: HashMap<u64, Inode> = HashMap::with_capacity(self.nodes.len());
+        let mut paths: HashMap<u64, PathBuf> = HashMap::new();
+        let mut stubs = HashSet::new();
+        for node in self.nodes {
+            if node.ino == FUSE_ROOT_ID {
+                paths.insert(node.ino, PathBuf::new());
+                inodes.insert(node.ino, Inode::with_ino(node.ino, node.ino, node.attr));
+                continue;
+            }
+            // Children of hidden entries and stubs are dropped along with them
+            if stubs.contains(&node.parent_ino) {
+                continue;
+            }
+            let Some(path) = paths.get(&node.parent_ino).map(|p| p.join(&node.attr.name)) else {
+                continue;
+            };
+            match sparse.visibility(&path, node.attr.kind == ContentType::Dir) {
+                Visibility::Hidden => continue,
+                Visibility::Stub => {
+                    stubs.insert(node.ino);
+                }
+                Visibility::Visible => {}
+            }
+            if let Some(parent) = inodes.get_mut(&node.parent_ino) {
+                parent.insert_child(node.ino);
+            }
+            paths.insert(node.ino, path);
+            inodes.insert(
+                node.ino,
+                Inode::with_ino(node.ino, node.parent_ino, node.attr),
+            );
+        }
+        inodes
+    }
+
+    /// Persist the snapshot under `cache_dir`, make it the latest one of its
+    /// repository and remove the oldest ones.
+    pub fn save(&self, cache_dir: &Path) -> io::Result<()> {
+        check_object_id(&self.commit_id)?;
+        let dir = Self::repo_dir(cache_dir, &self.repo);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", self.commit_id));
+        write_atomically(&path, &serde_json::to_vec(self)?)?;
+        write_atomically(&dir.join(LATEST), self.commit_id.as_bytes())?;
+        prune(&dir, &path)
+    }
+
+    /// Load the snapshot of `repo` at `commit_id` from `cache_dir`, or the
+    /// latest one without a `commit_id`.
+    pub fn load(cache_dir: &Path, repo: &str, commit_id: Option<&str>) -> io::Result<TreeSnapshot> {
+        let dir = Self::repo_dir(cache_dir, repo);
+        let commit_id = match commit_id {
+            Some(commit_id) => commit_id.to_owned(),
+            None => fs::read_to_string(dir.join(LATEST))?.trim().to_owned(),
+        };
+        check_object_id(&commit_id)?;
+        let content = fs::read(dir.join(format!("{}.json", commit_id)))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Load every snapshot of every repository in `cache_dir`, skipping the
+    /// unreadable ones.
+    pub fn load_all(cache_dir: &Path) -> Vec<TreeSnapshot> {
+        let Ok(repos) = fs::read_dir(cache_dir.join(TREES_DIR)) else {
+            return Vec::new();
+        };
+        repos
+            .filter_map(|repo| repo.ok())
+            .filter_map(|repo| fs::read_dir(repo.path()).ok())
+            .flatten()
+            .filter_map(|snapshot| snapshot.ok())
+            .filter(|snapshot| snapshot.path().extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|snapshot| fs::read(snapshot.path()).ok())
+            .filter_map(|content| serde_json::from_slice(&content).ok())
+            .collect()
+    }
+
+    /// Remove every snapshot of `repo` from `cache_dir`.
+    pub fn remove_repo(cache_dir: &Path, repo: &str) -> io::Result<()> {
+        match fs::remove_dir_all(Self::repo_dir(cache_dir, repo)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Directory holding the snapshots of `repo`.
+    pub fn repo_dir(cache_dir: &Path, repo: &str) -> PathBuf {
+        // Repository names may be nested paths
+        let name = repo.trim_matches('/').replace('/', "%2F");
+        cache_dir.join(TREES_DIR).join(name)
+    }
+}
+
+/// Children of the directories of a snapshot by object id. A directory with
+/// the same object id has the same content, so its subtree can be reused
+/// instead of being requested again.
+#[derive(Debug, Default)]
+pub struct SubtreeIndex {
+    children: HashMap<String, Vec<InodeAttributes>>,
+}
+
+impl SubtreeIndex {
+    /// Index the directories of `snapshot`, except its root. Identical
+    /// subtrees share their object id, only the first one loaded is indexed.
+    pub fn new(snapshot: &TreeSnapshot) -> SubtreeIndex {
+        let parents: HashSet<u64> = snapshot.nodes.iter().map(|node| node.parent_ino).collect();
+        let mut first: HashMap<&str, u64> = HashMap::new();
+        for node in &snapshot.nodes {
+            if node.ino != FUSE_ROOT_ID
+                && node.attr.kind == ContentType::Dir
+                && parents.contains(&node.ino)
+            {
+                first.entry(node.attr.id.as_str()).or_insert(node.ino);
+            }
+        }
+        let ids: HashMap<u64, &str> = first.into_iter().map(|(id, ino)| (ino, id)).collect();
+        let mut children: HashMap<String, Vec<InodeAttributes>> = HashMap::new();
+        for node in &snapshot.nodes {
+            if let Some(id) = ids.get(&node.parent_ino) {
+                children
+                    .entry(id.to_string())
+                    .or_default()
+                    .push(node.attr.clone());
+            }
+        }
+        SubtreeIndex { children }
+    }
+
+    /// Children of the directory with object id `id`. Git trees are never
+    /// empty, so directories without children in the snapshot were not loaded
+    /// (sparse stubs) and are not indexed.
+    pub fn children(&self, id: &str) -> Option<&[InodeAttributes]> {
+        self.children.get(id).map(Vec::as_slice)
+    }
+}
+
+/// Remove the snapshots in `dir` beyond the `KEPT_SNAPSHOTS` most recently
+/// written, never `latest`.
+fn prune(dir: &Path, latest: &Path) -> io::Result<()> {
+    let mut snapshots: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path != latest && path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+        .collect();
+    // Newest first
+    snapshots.sort_unstable_by(|a, b| b.cmp(a));
+    for (_, path) in snapshots.into_iter().skip(KEPT_SNAPSHOTS - 1) {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Write `content` to `path` through a temporary file renamed into place, so
+/// other mounts sharing the cache never see a partial file.
+pub fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    /// A commit id, made up from `n`
+    fn commit(n: u64) -> String {
+        format!("{:040x}", n)
+    }
+
+    fn attr(name: &str, kind: ContentType, commit_date: u64) -> InodeAttributes {
+        InodeAttributes {
+            id: format!("id-of-{}", name),
+            commit_id: format!("commit-{}", commit_date),
+            size: 0,
+            size_known: true,
+            name: name.to_owned(),
+            kind,
+            path: name.to_owned(),
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(commit_date),
+            ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(commit_date),
+            permissions: 0o644,
+        }
+    }
+
+    fn sample_tree() -> HashMap<u64, Inode> {
+        let mut root = Inode::root_node("fuser");
+        let src = Inode::new(FUSE_ROOT_ID, attr("src", ContentType::Dir, 2));
+        let mut docs = Inode::new(FUSE_ROOT_ID, attr("docs", ContentType::Dir, 1));
+        let readme = Inode::new(docs.ino, attr("README.md", ContentType::File, 1));
+        root.insert_child(src.ino);
+        root.insert_child(docs.ino);
+        docs.insert_child(readme.ino);
+        [root, src, docs, readme]
+            .into_iter()
+            .map(|inode| (inode.ino, inode))
+            .collect()
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let inodes = sample_tree();
+        let src_ino = inodes[&FUSE_ROOT_ID].children_ino[0];
+
+        let dir = std::env::temp_dir().join(format!("mega-fuse-snapshot-{}", std::process::id()));
+        TreeSnapshot::capture(
+            "nested/fuser",
+            &commit(2),
+            &SparseProfile::default(),
+            &inodes,
+        )
+        .save(&dir)
+        .unwrap();
+        let latest = TreeSnapshot::load(&dir, "nested/fuser", None).unwrap();
+        assert_eq!(latest.commit_id(), commit(2));
+        let snapshot = TreeSnapshot::load(&dir, "nested/fuser", Some(&commit(2))).unwrap();
+        assert!(TreeSnapshot::load(&dir, "nested/fuser", Some(&commit(1))).is_err());
+
+        // Older snapshots are pruned, the latest one is kept
+        for id in [3, 4, 5].map(commit) {
+            TreeSnapshot::capture("nested/fuser", &id, &SparseProfile::default(), &inodes)
+                .save(&dir)
+                .unwrap();
+        }
+        let kept = fs::read_dir(TreeSnapshot::repo_dir(&dir, "nested/fuser"))
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .unwrap_or_default()
+                    == "json"
+            })
+            .count();
+        assert_eq!(kept, KEPT_SNAPSHOTS);
+        assert!(TreeSnapshot::load(&dir, "nested/fuser", Some(&commit(5))).is_ok());
+        assert_eq!(
+            TreeSnapshot::load(&dir, "nested/fuser", None)
+                .unwrap()
+                .commit_id(),
+            commit(5)
+        );
+        // Commit ids name the snapshot files
+        let escaping =
+            TreeSnapshot::capture("fuser", "../../x", &SparseProfile::default(), &inodes);
+        assert!(escaping.save(&dir).is_err());
+        assert!(TreeSnapshot::load(&dir, "nested/fuser", Some("../LATEST")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+
+        let restored = snapshot.restore(&SparseProfile::new(["src"], [] as [&str; 0], false));
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[&FUSE_ROOT_ID].children_ino, vec![src_ino]);
+        assert_eq!(restored[&src_ino].attr.name, "src");
+    }
+
+    #[test]
+    fn test_subtree_index() {
+        let inodes = sample_tree();
+        let snapshot =
+            TreeSnapshot::capture("fuser", &commit(2), &SparseProfile::default(), &inodes);
+        let index = SubtreeIndex::new(&snapshot);
+
+        let docs = index.children("id-of-docs").unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].name, "README.md");
+        // Never loaded, so not known to be empty
+        assert!(index.children("id-of-src").is_none());
+    }
+
+    #[test]
+    fn test_subtree_index_shared_id() {
+        let mut inodes = sample_tree();
+        // A copy of `docs` with the same content, so the same object id
+        let mut copy = Inode::new(FUSE_ROOT_ID, attr("docs", ContentType::Dir, 1));
+        copy.attr.name = "docs-copy".to_owned();
+        let readme = Inode::new(copy.ino, attr("README.md", ContentType::File, 1));
+        copy.insert_child(readme.ino);
+        inodes
+            .get_mut(&FUSE_ROOT_ID)
+            .unwrap()
+            .insert_child(copy.ino);
+        inodes.insert(copy.ino, copy);
+        inodes.insert(readme.ino, readme);
+
+        let snapshot =
+            TreeSnapshot::capture("fuser", &commit(2), &SparseProfile::default(), &inodes);
+        let index = SubtreeIndex::new(&snapshot);
+        assert_eq!(index.children("id-of-docs").unwrap().len(), 1);
+    }
+}
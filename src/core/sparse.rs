@@ -0,0 +1,243 @@
+//! Sparse checkout profiles: restrict the mounted tree to the directories a
+//! team cares about. Patterns follow git's sparse-checkout cone mode, every
+//! pattern names a directory relative to the repository root:
+//! - an included directory is exposed recursively,
+//! - directories leading to an included one are exposed together with the
+//!   files they directly contain,
+//! - an excluded directory is hidden, unless a more specific include lies
+//!   beneath it.
+//!
+//! Directories outside of the profile are either hidden or shown as empty
+//! stubs.
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How an entry of the tree is exposed under a `SparseProfile`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    /// Exposed with all of its content
+    Visible,
+    /// Exposed as an empty directory
+    Stub,
+    /// Not exposed at all, lookups return `ENOENT`
+    Hidden,
+}
+
+/// Include and exclude directory patterns of a sparse checkout
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SparseProfile {
+    include: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+    stubs: bool,
+}
+
+impl SparseProfile {
+    /// Build a profile from include and exclude patterns. Leading and trailing
+    /// slashes are ignored. With `stubs`, directories outside of the profile
+    /// appear as empty directories instead of being hidden.
+    pub fn new<I, E>(include: I, exclude: E, stubs: bool) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+        E: IntoIterator,
+        E::Item: AsRef<str>,
+    {
+        SparseProfile {
+            include: include.into_iter().map(normalize).collect(),
+            exclude: exclude.into_iter().map(normalize).collect(),
+            stubs,
+        }
+    }
+
+    /// Read a profile file in git sparse-checkout format: one directory per
+    /// line, `!` prefixes an exclude, `#` starts a comment. The files written
+    /// by `git sparse-checkout set` in cone mode are understood as well:
+    /// `/*` and `!/*/` keep the top level files, `/dir/` followed by
+    /// `!/dir/*/` keeps the files of `dir` on the way to an included
+    /// directory. Other wildcards are rejected.
+    pub fn from_file(path: &Path, stubs: bool) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        // Directories whose subdirectories are excluded, by `!/dir/*/`
+        let mut parents = Vec::new();
+        let mut cone = false;
+        for (number, line) in content.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line),
+            };
+            let wildcard = |pattern: &str| pattern.contains(['*', '?', '[', '\\']);
+            match (negated, pattern.strip_suffix("/*/")) {
+                (false, _) if pattern == "/*" => cone = true,
+                (true, Some("")) => cone = true,
+                (true, Some(parent)) if !wildcard(parent) => parents.push(normalize(parent)),
+                _ if wildcard(pattern) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: {} is not a directory pattern", number + 1, line),
+                    ));
+                }
+                (true, _) => exclude.push(normalize(pattern)),
+                (false, _) => include.push(normalize(pattern)),
+            }
+        }
+        // Parents are exposed anyway on the way to the included directories
+        include.retain(|dir| !parents.contains(dir));
+        if cone && include.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no directory is included",
+            ));
+        }
+        Ok(SparseProfile {
+            include,
+            exclude,
+            stubs,
+        })
+    }
+
+    /// Merge the patterns of `other` into this profile.
+    pub fn extend(&mut self, other: SparseProfile) {
+        self.include.extend(other.include);
+        self.exclude.extend(other.exclude);
+        self.stubs |= other.stubs;
+    }
+
+    /// Whether the profile exposes the whole tree.
+    pub fn is_full(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Visibility of the entry at `path`, relative to the repository root.
+    pub fn visibility(&self, path: &Path, is_dir: bool) -> Visibility {
+        if self.is_full() {
+            return Visibility::Visible;
+        }
+        let depth = |pattern: &PathBuf| pattern.components().count();
+
+        // The most specific matching pattern wins, excludes win ties
+        let included = self
+            .include
+            .iter()
+            .filter_map(|pattern| {
+                if path.starts_with(pattern) {
+                    Some(depth(pattern))
+                } else if is_dir && pattern.starts_with(path) {
+                    // On the way to an included directory
+                    Some(depth(pattern))
+                } else if !is_dir && path.parent().is_some_and(|p| pattern.starts_with(p)) {
+                    // Files next to the way to an included directory
+                    Some(depth(pattern) - 1)
+                } else {
+                    None
+                }
+            })
+            .max();
+        let excluded = self
+            .exclude
+            .iter()
+            .filter(|pattern| path.starts_with(pattern))
+            .map(depth)
+            .max();
+
+        let visible = match (included, excluded) {
+            (_, Some(excluded)) => included.is_some_and(|included| included > excluded),
+            (Some(_), None) => true,
+            (None, None) => self.include.is_empty(),
+        };
+        match (visible, is_dir && self.stubs) {
+            (true, _) => Visibility::Visible,
+            (false, true) => Visibility::Stub,
+            (false, false) => Visibility::Hidden,
+        }
+    }
+}
+
+fn normalize<S: AsRef<str>>(pattern: S) -> PathBuf {
+    Path::new(pattern.as_ref())
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_profile() {
+        let profile = SparseProfile::default();
+        assert!(profile.is_full());
+        assert_eq!(
+            profile.visibility(Path::new("src/main.rs"), false),
+            Visibility::Visible
+        );
+    }
+
+    #[test]
+    fn test_cone_mode() {
+        let profile = SparseProfile::new(["/src/core/"], ["src/core/tests"], false);
+        let visibility = |path: &str, is_dir| profile.visibility(Path::new(path), is_dir);
+
+        // Top level files and the way to included directories are visible
+        assert_eq!(visibility("Cargo.toml", false), Visibility::Visible);
+        assert_eq!(visibility("src", true), Visibility::Visible);
+        assert_eq!(visibility("src/lib.rs", false), Visibility::Visible);
+        assert_eq!(visibility("src/core", true), Visibility::Visible);
+        assert_eq!(
+            visibility("src/core/deep/mod.rs", false),
+            Visibility::Visible
+        );
+
+        assert_eq!(visibility("docs", true), Visibility::Hidden);
+        assert_eq!(visibility("docs/README.md", false), Visibility::Hidden);
+        assert_eq!(visibility("src/cli", true), Visibility::Hidden);
+        assert_eq!(visibility("src/core/tests", true), Visibility::Hidden);
+    }
+
+    #[test]
+    fn test_cone_mode_file() {
+        let path = std::env::temp_dir().join(format!("mega-fuse-sparse-{}", std::process::id()));
+        // Written by `git sparse-checkout set src/core docs`
+        fs::write(
+            &path,
+            "/*\n!/*/\n/docs/\n/src/\n!/src/*/\n/src/core/\n# local\n!src/core/tests\n",
+        )
+        .unwrap();
+        let profile = SparseProfile::from_file(&path, false).unwrap();
+        assert_eq!(
+            profile,
+            SparseProfile::new(["docs", "src/core"], ["src/core/tests"], false)
+        );
+        assert_eq!(
+            profile.visibility(Path::new("src/cli"), true),
+            Visibility::Hidden
+        );
+
+        for invalid in ["*.rs\n", "/src/**/gen/\n", "/*\n!/*/\n"] {
+            fs::write(&path, invalid).unwrap();
+            assert!(SparseProfile::from_file(&path, false).is_err());
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_stubs_and_nested_include() {
+        let profile = SparseProfile::new(["vendor/keep"], ["vendor"], true);
+        let visibility = |path: &str, is_dir| profile.visibility(Path::new(path), is_dir);
+
+        assert_eq!(visibility("vendor", true), Visibility::Visible);
+        assert_eq!(visibility("vendor/keep/lib.rs", false), Visibility::Visible);
+        assert_eq!(visibility("vendor/other", true), Visibility::Stub);
+        assert_eq!(visibility("vendor/README", false), Visibility::Hidden);
+        assert_eq!(visibility("docs", true), Visibility::Stub);
+    }
+}
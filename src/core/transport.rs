@@ -0,0 +1,535 @@
+//! Connections to the Mega server, shared by every request of a `MegaClient`
+//! and its prefetch workers. Servers speaking HTTP/2 in plain text (h2c, with
+//! prior knowledge) get a single connection multiplexing every request;
+//! otherwise requests go through a pool of HTTP/1 connections, one request at a
+//! time each.
+//!
+//! Responses may come compressed with gzip or zstd, as negotiated with
+//! `Accept-Encoding`, and are decoded before being handed out.
+//!
+//! Connecting, waiting for the head of a response and whole requests are each
+//! bounded by the configured `RequestTimeouts`, failing with `Timeout`.
+//! Responses with an error status fail with `HttpStatus`.
+use std::{
+    fmt,
+    future::Future,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    body::Incoming,
+    client::conn::{http1, http2},
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING},
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpStream;
+use tracing::{debug, debug_span, field, warn, Instrument};
+
+use crate::{
+    cli::{Compression, HttpVersion},
+    config::RequestTimeouts,
+    core::metrics,
+};
+
+/// How long the server has to answer the HTTP/2 probe before HTTP/1 is used
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Idle HTTP/1 connections kept open beyond this are closed
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+type Body = Empty<Bytes>;
+
+/// A request to the Mega server gave up after `limit`
+#[derive(Debug)]
+pub struct Timeout {
+    /// What took too long: `connect`, `first byte` or `request`
+    pub stage: &'static str,
+    /// The limit exceeded
+    pub limit: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.stage, self.limit)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// The Mega server answered a request with a status other than success
+#[derive(Debug)]
+pub struct HttpStatus {
+    /// Path of the request
+    pub endpoint: String,
+    /// Status of the response
+    pub status: StatusCode,
+}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} answered {}", self.endpoint, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// Await `future`, failing with `Timeout` for `stage` once `limit` elapses.
+async fn within<T, E: Into<anyhow::Error>>(
+    stage: &'static str,
+    limit: Duration,
+    future: impl Future<Output = std::result::Result<T, E>>,
+) -> Result<T> {
+    match tokio::time::timeout(limit, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(Timeout { stage, limit }.into()),
+    }
+}
+
+/// Cheap to clone handle on the connections to one server
+#[derive(Clone, Debug)]
+pub struct Transport {
+    addr: Arc<str>,
+    http2: bool,
+    /// `Accept-Encoding` sent with every request, if any
+    accept_encoding: Option<HeaderValue>,
+    timeouts: RequestTimeouts,
+    /// The HTTP/2 connection, reopened once closed
+    h2: Arc<Mutex<Option<http2::SendRequest<Body>>>>,
+    /// HTTP/1 connections waiting for a request
+    idle: Arc<Mutex<Vec<http1::SendRequest<Body>>>>,
+}
+
+impl Transport {
+    /// Connect to `addr` with `version`, asking for `compression` and giving up
+    /// on requests after `timeouts`. With `HttpVersion::Auto`, HTTP/2 is used
+    /// if the server answers a request over it, HTTP/1 otherwise.
+    pub async fn connect(
+        addr: &str,
+        version: HttpVersion,
+        compression: Compression,
+        timeouts: RequestTimeouts,
+    ) -> Result<Transport> {
+        let accept_encoding = match compression {
+            Compression::Auto => Some("zstd, gzip"),
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::None => None,
+        };
+        let mut transport = Transport {
+            addr: addr.into(),
+            http2: false,
+            accept_encoding: accept_encoding.map(HeaderValue::from_static),
+            timeouts,
+            h2: Arc::default(),
+            idle: Arc::default(),
+        };
+        let connect = timeouts.connect;
+        match version {
+            HttpVersion::Http1 => transport.idle_push(connect_http1(addr, connect).await?),
+            HttpVersion::Http2 => {
+                transport.http2 = true;
+                *transport.h2.lock().unwrap() = Some(connect_http2(addr, connect).await?);
+            }
+            HttpVersion::Auto => match probe_http2(addr, connect).await {
+                Ok(sender) => {
+                    transport.http2 = true;
+                    *transport.h2.lock().unwrap() = Some(sender);
+                }
+                Err(err) => {
+                    debug!("HTTP/2 unavailable on {}, using HTTP/1: {:#}", addr, err);
+                    transport.idle_push(connect_http1(addr, connect).await?);
+                }
+            },
+        }
+        Ok(transport)
+    }
+
+    /// Protocol spoken with the server.
+    pub fn protocol(&self) -> &'static str {
+        match self.http2 {
+            true => "HTTP/2",
+            false => "HTTP/1.1",
+        }
+    }
+
+    /// Send `req` and collect the whole body of the response, decoded.
+    pub async fn send(&self, mut req: Request<Body>) -> Result<Bytes> {
+        if let Some(accept_encoding) = &self.accept_encoding {
+            req.headers_mut()
+                .insert(ACCEPT_ENCODING, accept_encoding.clone());
+        }
+        let endpoint = req.uri().path().to_owned();
+        let span = debug_span!(
+            "http",
+            endpoint,
+            query = req.uri().query(),
+            status = field::Empty,
+            bytes = field::Empty,
+            encoding = field::Empty
+        );
+        let start = Instant::now();
+        let exchange = self.exchange(req).instrument(span.clone());
+        let result = within("request", self.timeouts.total, exchange).await;
+
+        let metrics = metrics::global();
+        let (status, encoding, body) = match result {
+            Ok(response) => response,
+            Err(err) => {
+                metrics.record_http(&endpoint, "error", start.elapsed());
+                return Err(err);
+            }
+        };
+        span.record("status", status.as_u16());
+        metrics.record_http(&endpoint, status.as_str(), start.elapsed());
+        // Bytes on the wire, before decoding
+        metrics.record_download(body.len());
+        // Error pages are no content
+        if !status.is_success() {
+            return Err(HttpStatus { endpoint, status }.into());
+        }
+        let body = match &encoding {
+            Some(encoding) => {
+                span.record("encoding", encoding.to_str().unwrap_or_default());
+                decode(encoding, body)?
+            }
+            None => body,
+        };
+        span.record("bytes", body.len());
+        Ok(body)
+    }
+
+    async fn exchange(&self, req: Request<Body>) -> Result<Exchanged> {
+        if self.http2 {
+            let mut sender = self.http2_sender().await?;
+            let response = sender.send_request(req);
+            return collect(within("first byte", self.timeouts.first_byte, response).await?).await;
+        }
+        let mut sender = self.http1_sender().await?;
+        let response = sender.send_request(req);
+        let response =
+            collect(within("first byte", self.timeouts.first_byte, response).await?).await?;
+        // Ready for another request once the body is read
+        self.idle_push(sender);
+        Ok(response)
+    }
+
+    async fn http2_sender(&self) -> Result<http2::SendRequest<Body>> {
+        let current = self.h2.lock().unwrap().clone();
+        match current.filter(|sender| !sender.is_closed()) {
+            Some(sender) => Ok(sender),
+            None => {
+                let sender = connect_http2(&self.addr, self.timeouts.connect).await?;
+                *self.h2.lock().unwrap() = Some(sender.clone());
+                Ok(sender)
+            }
+        }
+    }
+
+    async fn http1_sender(&self) -> Result<http1::SendRequest<Body>> {
+        loop {
+            let Some(sender) = self.idle.lock().unwrap().pop() else {
+                break;
+            };
+            if sender.is_ready() {
+                return Ok(sender);
+            }
+        }
+        connect_http1(&self.addr, self.timeouts.connect).await
+    }
+
+    fn idle_push(&self, sender: http1::SendRequest<Body>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(sender);
+        }
+    }
+}
+
+/// Status, `Content-Encoding` and raw body of a response
+type Exchanged = (StatusCode, Option<HeaderValue>, Bytes);
+
+async fn collect(response: Response<Incoming>) -> Result<Exchanged> {
+    let status = response.status();
+    let encoding = response.headers().get(CONTENT_ENCODING).cloned();
+    let body = response.collect().await?.to_bytes();
+    Ok((status, encoding, body))
+}
+
+/// Undo the content codings listed in `encoding`, applied in order.
+fn decode(encoding: &HeaderValue, body: Bytes) -> Result<Bytes> {
+    let mut body = body;
+    for coding in encoding.to_str()?.rsplit(',').map(str::trim) {
+        body = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => body,
+            "gzip" | "x-gzip" => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(&body[..]).read_to_end(&mut decoded)?;
+                decoded.into()
+            }
+            "zstd" => zstd::decode_all(&body[..])?.into(),
+            coding => bail!("unsupported content encoding {:?}", coding),
+        };
+    }
+    Ok(body)
+}
+
+/// Dial `addr` and complete an HTTP/1 handshake, within `limit`. The
+/// connection is driven by a task spawned onto the current runtime.
+async fn connect_http1(addr: &str, limit: Duration) -> Result<http1::SendRequest<Body>> {
+    let io = TokioIo::new(within("connect", limit, TcpStream::connect(addr)).await?);
+    let (sender, conn) = http1::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            warn!("HTTP/1 connection failed: {:?}", err);
+        }
+    });
+    Ok(sender)
+}
+
+/// Dial `addr` within `limit` and start HTTP/2 with prior knowledge. Whether
+/// the server speaks it is only known once it answers.
+async fn connect_http2(addr: &str, limit: Duration) -> Result<http2::SendRequest<Body>> {
+    let io = TokioIo::new(within("connect", limit, TcpStream::connect(addr)).await?);
+    let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("HTTP/2 connection closed: {:?}", err);
+        }
+    });
+    Ok(sender)
+}
+
+/// Connect over HTTP/2 and check the server answers a request over it.
+async fn probe_http2(addr: &str, connect: Duration) -> Result<http2::SendRequest<Body>> {
+    let mut sender = connect_http2(addr, connect).await?;
+    let probe = Request::head("/").body(Body::new())?;
+    tokio::time::timeout(PROBE_TIMEOUT, sender.send_request(probe))
+        .await
+        .map_err(|_| anyhow!("no answer within {:?}", PROBE_TIMEOUT))??;
+    Ok(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use http_body_util::Full;
+    use hyper::service::service_fn;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn request() -> Request<Body> {
+        Request::get("/api/v1/blob").body(Body::new()).unwrap()
+    }
+
+    /// An HTTP/1 only server answering `response` to whatever it reads
+    fn serve_http1(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 4096];
+                    while stream.read(&mut buf).is_ok_and(|n| n > 0) {
+                        if stream.write_all(response).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_http1_fallback() {
+        let rt = runtime();
+        let addr = serve_http1(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let transport = rt
+            .block_on(Transport::connect(
+                &addr,
+                HttpVersion::Auto,
+                Compression::Auto,
+                RequestTimeouts::default(),
+            ))
+            .unwrap();
+        assert_eq!(transport.protocol(), "HTTP/1.1");
+        for _ in 0..3 {
+            assert_eq!(rt.block_on(transport.send(request())).unwrap(), "ok");
+        }
+        // Sequential requests reuse the pooled connection
+        assert_eq!(transport.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_http2() {
+        let rt = runtime();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(Mutex::new(0));
+        let accepted = connections.clone();
+        rt.spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                *accepted.lock().unwrap() += 1;
+                let service = service_fn(|_req| async {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("h2"))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let transport = rt
+            .block_on(Transport::connect(
+                &addr,
+                HttpVersion::Auto,
+                Compression::Auto,
+                RequestTimeouts::default(),
+            ))
+            .unwrap();
+        assert_eq!(transport.protocol(), "HTTP/2");
+        let responses = rt.block_on(async {
+            let requests = (0..8).map(|_| {
+                let transport = transport.clone();
+                tokio::spawn(async move { transport.send(request()).await })
+            });
+            let mut responses = Vec::new();
+            for request in requests.collect::<Vec<_>>() {
+                responses.push(request.await.unwrap().unwrap());
+            }
+            responses
+        });
+        assert!(responses.iter().all(|body| body == "h2"));
+        // Concurrent requests were multiplexed over the probed connection
+        assert_eq!(*connections.lock().unwrap(), 1);
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let content = Bytes::from_static(b"{\"name\":\"src\"}");
+        let gzip = Bytes::from(gzip(&content));
+        let zstd = Bytes::from(zstd::encode_all(&content[..], 0).unwrap());
+        let decode = |encoding: &'static str, body: &Bytes| {
+            decode(&HeaderValue::from_static(encoding), body.clone())
+        };
+        assert_eq!(decode("gzip", &gzip).unwrap(), content);
+        assert_eq!(decode("X-Gzip", &gzip).unwrap(), content);
+        assert_eq!(decode("zstd", &zstd).unwrap(), content);
+        assert_eq!(decode("identity", &content).unwrap(), content);
+        // Codings listed in the order they were applied
+        let both = Bytes::from(zstd::encode_all(&gzip[..], 0).unwrap());
+        assert_eq!(decode("gzip, zstd", &both).unwrap(), content);
+        assert!(decode("br", &content).is_err());
+        assert!(decode("zstd", &content).is_err());
+    }
+
+    #[test]
+    fn test_compressed_response() {
+        let rt = runtime();
+        let body = gzip(b"ok");
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body);
+        let addr = serve_http1(response.leak());
+        let transport = rt
+            .block_on(Transport::connect(
+                &addr,
+                HttpVersion::Http1,
+                Compression::Gzip,
+                RequestTimeouts::default(),
+            ))
+            .unwrap();
+        assert_eq!(rt.block_on(transport.send(request())).unwrap(), "ok");
+    }
+
+    #[test]
+    fn test_error_status() {
+        let rt = runtime();
+        let addr = serve_http1(b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found");
+        let transport = rt
+            .block_on(Transport::connect(
+                &addr,
+                HttpVersion::Http1,
+                Compression::None,
+                RequestTimeouts::default(),
+            ))
+            .unwrap();
+        let err = rt.block_on(transport.send(request())).unwrap_err();
+        let status = err.downcast_ref::<HttpStatus>().unwrap();
+        assert_eq!(status.status, StatusCode::NOT_FOUND);
+        assert_eq!(status.endpoint, "/api/v1/blob");
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let rt = runtime();
+        // Reads requests, never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for stream in listener.incoming() {
+                streams.push(stream.unwrap());
+            }
+        });
+        let timeouts = RequestTimeouts {
+            first_byte: Duration::from_millis(100),
+            ..RequestTimeouts::default()
+        };
+        let transport = rt
+            .block_on(Transport::connect(
+                &addr,
+                HttpVersion::Http1,
+                Compression::None,
+                timeouts,
+            ))
+            .unwrap();
+        let err = rt.block_on(transport.send(request())).unwrap_err();
+        let timeout = err.downcast_ref::<Timeout>().unwrap();
+        assert_eq!(timeout.stage, "first byte");
+
+        let transport = Transport {
+            timeouts: RequestTimeouts {
+                total: Duration::from_millis(50),
+                ..timeouts
+            },
+            ..transport
+        };
+        let err = rt.block_on(transport.send(request())).unwrap_err();
+        assert_eq!(err.downcast_ref::<Timeout>().unwrap().stage, "request");
+    }
+}
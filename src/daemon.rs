@@ -0,0 +1,62 @@
+//! Detaching from the terminal once mounted. `mount(8)` waits for its helpers
+//! to exit, so the process forks: the parent stays until the child reports
+//! the file system mounted, then exits with the outcome.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    process,
+};
+
+/// Held by the detached child to report once the file system is mounted
+#[derive(Debug)]
+pub struct Readiness {
+    pipe: File,
+}
+
+impl Readiness {
+    /// Let the parent exit successfully, then detach the standard streams.
+    pub fn notify(mut self) {
+        let _ = self.pipe.write_all(&[0]);
+        if let Ok(null) = OpenOptions::new().read(true).write(true).open("/dev/null") {
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                // SAFETY: both descriptors are valid
+                unsafe { libc::dup2(null.as_raw_fd(), fd) };
+            }
+        }
+    }
+}
+
+/// Fork into a new session. Only the child returns; the parent exits with 0
+/// once notified through the returned `Readiness`, or 1 if the child exits
+/// or drops it first. Must be called before any thread is spawned.
+pub fn daemonize() -> io::Result<Readiness> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just created and are owned here only
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: the process is still single threaded
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read_end);
+            // SAFETY: plain system call, the child is no process group leader
+            unsafe { libc::setsid() };
+            Ok(Readiness { pipe: write_end })
+        }
+        _ => {
+            drop(write_end);
+            let mut ready = [0; 1];
+            let status = match read_end.read(&mut ready) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            process::exit(status)
+        }
+    }
+}
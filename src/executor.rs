@@ -1,11 +1,14 @@
+use std::{path::Path, process};
+
 use clap::Command;
-use fuser::{mount2, spawn_mount2, MountOption};
-use tracing::info;
+use fuser::Session;
+use tracing::{error, info, warn};
 
 use crate::{
-    cli::{parse, Commands},
+    cli::{parse, CacheCommands, Commands},
     config,
-    core::{mega_client, MegaFUSE},
+    core::{api::CommitRequest, cache::BlobCache, mega_client, metrics, MegaFUSE},
+    daemon, logging,
 };
 
 /// Executor contains the actual logic of `mega-fuse`
@@ -26,22 +29,47 @@ pub struct Executor {}
 impl Executor {
     /// Executor entrance
     pub fn start() {
-        // Initialize tracing subscriber
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .init();
-        info!("Tracing subscriber initialized");
-
         // Parse command line arguments
         let cli = parse();
-        info!("Command line arguments parsed: {:?}", cli);
 
         // Construct `Config` from `Args`
-        let config = config::Config::from(&cli);
+        let mut config = config::Config::from(&cli);
+
+        // Read configuration from configuration file, the command line takes
+        // precedence
+        if let Some(path) = &cli.config {
+            let file =
+                config::ConfigFile::load(path).expect("Failed to read the configuration file");
+            config.merge(file);
+        }
+
+        // Detach before any thread is spawned, the parent exits once mounted
+        let readiness = match &cli.command {
+            Commands::Connect { .. } if cli.background => {
+                Some(daemon::daemonize().expect("Failed to detach from the terminal"))
+            }
+            _ => None,
+        };
+
+        // Initialize tracing subscriber, file logs are flushed when the guard
+        // drops
+        let log_settings = config
+            .validated_log()
+            .expect("Invalid --log-level or missing --log-dir");
+        let _log_guard = logging::init(&log_settings).expect("Failed to initialize logging");
+        info!("Tracing subscriber initialized");
+        info!("Command line arguments parsed: {:?}", cli);
         info!("`Config` generated from cli: {:?}", config);
 
-        // Read configuration from configuration file
-        // TODO: Currently skipped
+        // Cache maintenance only needs the cache directory, whether or not a
+        // repository is mounted
+        if let Commands::Cache { command } = &cli.command {
+            let cache_dir = config
+                .validated_cache_dir()
+                .expect("An existing --cache-dir is required to maintain the cache");
+            Self::maintain_cache(command, &cache_dir);
+            return;
+        }
 
         // Validate `Config`
         // The `ValidatedConfig::from()` method will use methods in `Config` to validate
@@ -52,33 +80,127 @@ impl Executor {
             validated_config
         );
 
-        // Construct `MegaClient`
-        let mut mega_client = mega_client::MegaClient::from_default_runtime(&validated_config)
-            .expect("The MegaClient should be construct faultlessly with validated config");
-        // If construction went successfully, the remote is alive at least this
-        // moment, because the mega_client is running on a long held TcpStream
-        info!(
-            "MegaClient connection established to mage server at {}",
-            &validated_config.server_url
-        );
+        // Construct `MegaClient`, falling back to offline mode if the remote is
+        // unreachable
+        let mut mega_client = if validated_config.offline {
+            info!("Offline mode, the mega server will not be contacted");
+            None
+        } else {
+            match mega_client::MegaClient::from_default_runtime(&validated_config) {
+                Ok(mega_client) => {
+                    // If construction went successfully, the remote is alive at
+                    // least this moment, because the mega_client is running on a
+                    // long held TcpStream
+                    info!(
+                        "MegaClient connection established to mage server at {} over {}",
+                        &validated_config.server_url,
+                        mega_client.protocol()
+                    );
+                    Some(mega_client)
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to connect to mega server at {}: {}, falling back to offline mode",
+                        &validated_config.server_url, err
+                    );
+                    None
+                }
+            }
+        };
 
         // Construct MegaFUSE
         match cli.command {
-            Commands::Connect { target } => {
+            Commands::Connect { target, .. } => {
                 info!("Connecting to {} at remote", target);
-                let fs = MegaFUSE::from(target, mega_client);
-                // let bs = spawn_mount2(fs, validated_config.mount_point,
-                // &vec![MountOption::RO]);
-                mount2(
+                // A missing repository fails here rather than in `init`, once
+                // the mount point is taken
+                if let Some(mega_client) = mega_client.as_mut() {
+                    let head = CommitRequest {
+                        repo: target.clone(),
+                        path: None,
+                    };
+                    match mega_client.call(&head) {
+                        Ok(commit) => info!("{} is at commit {}", target, commit.oid),
+                        Err(err) => {
+                            error!(
+                                "Cannot mount {} from {}: {:#}",
+                                target, &validated_config.server_url, err
+                            );
+                            process::exit(1);
+                        }
+                    }
+                }
+                if let Some(addr) = validated_config.metrics_addr {
+                    if let Err(err) = metrics::serve(addr) {
+                        warn!("Failed to serve metrics on {}: {}", addr, err);
+                    }
+                }
+                let fs = MegaFUSE::from(target, mega_client, &validated_config);
+                let notifier = fs.notifier();
+                let mut session = Session::new(
                     fs,
-                    validated_config.mount_point,
-                    &vec![MountOption::RO, MountOption::FSName("MegaFUSE".to_string())],
+                    &validated_config.mount_point,
+                    &validated_config.mount_options,
                 )
                 .unwrap();
+                let _ = notifier.set(session.notifier());
+                match readiness {
+                    Some(readiness) => {
+                        let session = session.spawn().unwrap();
+                        readiness.notify();
+                        session.join();
+                    }
+                    None => session.run().unwrap(),
+                }
             }
             Commands::Disconnect { target } => {
                 info!("Disconnecting from {}", target);
             }
+            Commands::Cache { .. } => unreachable!("handled before connecting"),
+        }
+    }
+
+    /// Run a cache maintenance operation on the cache in `cache_dir`
+    fn maintain_cache(command: &CacheCommands, cache_dir: &Path) {
+        let cache = BlobCache::new(cache_dir);
+        match command {
+            CacheCommands::Stats => {
+                let stats = cache.stats();
+                println!("blobs:     {}", stats.blobs);
+                println!("size:      {} bytes", stats.bytes);
+                println!("hits:      {}", stats.hits);
+                println!("misses:    {}", stats.misses);
+                match stats.hit_ratio() {
+                    Some(ratio) => println!("hit ratio: {:.1}%", ratio * 100.0),
+                    None => println!("hit ratio: -"),
+                }
+            }
+            CacheCommands::Prune {
+                max_size,
+                older_than,
+            } => {
+                let (removed, freed) = cache
+                    .prune(*max_size, *older_than)
+                    .expect("Failed to prune the cache");
+                println!("removed {} blobs, {} bytes freed", removed, freed);
+            }
+            CacheCommands::Verify => {
+                let corrupted = cache.verify().expect("Failed to verify the cache");
+                for id in &corrupted {
+                    println!("corrupted: {}", id);
+                }
+                println!("{} corrupted blobs removed", corrupted.len());
+            }
+            CacheCommands::Clear { repo: Some(repo) } => {
+                let removed = cache
+                    .clear_repo(repo)
+                    .expect("Failed to clear the cache of the repo");
+                println!("removed {} blobs of {}", removed, repo);
+            }
+            CacheCommands::Clear { repo: None } => {
+                cache.clear().expect("Failed to clear the cache");
+                println!("cache cleared");
+            }
         }
     }
 }
@@ -19,5 +19,9 @@ pub mod cli;
 pub mod config;
 /// Core logic
 pub mod core;
+/// Detaching from the terminal
+pub mod daemon;
 /// Executor for cli module
 pub mod executor;
+/// Logging setup
+pub mod logging;
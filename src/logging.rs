@@ -0,0 +1,99 @@
+//! Tracing subscriber setup: where log lines go, in which format and which of
+//! them are kept. Filters follow `RUST_LOG` syntax, so FUSE operations can be
+//! traced on their own with e.g. `info,fuse::core=debug`.
+//!
+//! Every FUSE request runs in a `debug` span named after the operation, with
+//! the HTTP requests and cache accesses it causes as child spans. Closed spans
+//! are logged with their duration, and can be written to a Chrome trace file
+//! to be inspected in `chrome://tracing` or Perfetto.
+use anyhow::Result;
+use tracing::Level;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    prelude::*,
+    EnvFilter, Layer,
+};
+
+use crate::{
+    cli::{LogFormat, LogRotation},
+    config::LogSettings,
+};
+
+/// Log files are named `<prefix>.<date>.<suffix>`
+const LOG_FILE_PREFIX: &str = "mega-fuse";
+const LOG_FILE_SUFFIX: &str = "log";
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Flushes the log and trace files when dropped, must be held until exit.
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    _trace: Option<FlushGuard>,
+}
+
+/// Install the global subscriber. Lines bound to a log file are written by a
+/// background thread.
+pub fn init(settings: &LogSettings) -> Result<LogGuard> {
+    let filter = EnvFilter::try_new(&settings.filter)?;
+    let (writer, file_guard) = match &settings.dir {
+        Some(dir) => {
+            let mut builder = RollingFileAppender::builder()
+                .rotation(settings.rotation.into())
+                .filename_prefix(LOG_FILE_PREFIX)
+                .filename_suffix(LOG_FILE_SUFFIX);
+            if let Some(max_files) = settings.max_files {
+                builder = builder.max_log_files(max_files);
+            }
+            let (writer, guard) = tracing_appender::non_blocking(builder.build(dir)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        // No escape codes in files
+        .with_ansi(settings.dir.is_none())
+        .with_span_events(FmtSpan::CLOSE);
+    let fmt = match settings.format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+
+    // The trace records the spans of this crate whatever the log filter
+    let (chrome, trace_guard) = match &settings.trace_file {
+        Some(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .file(path)
+                .include_args(true)
+                .build();
+            let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::DEBUG);
+            (Some(layer.with_filter(targets)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(chrome)
+        .init();
+    Ok(LogGuard {
+        _file: file_guard,
+        _trace: trace_guard,
+    })
+}